@@ -9,7 +9,12 @@ logging.basicConfig(level=logging.DEBUG, stream=stderr)
 
 initializing = False
 reading_map = False
+game_over = False
 player_id = None
+tick = 0
+edge_mode = "wall"
+# everything else the INIT block tells, like arena_width or head_speed
+game_config = {}
 snakes = []
 foods = []
 
@@ -17,6 +22,8 @@ foods = []
 class Snake:
     def __init__(self, player_id):
         self.player_id = player_id
+        self.velocity = (0.0, 0.0)
+        self.alive = True
         self.segments = []
 
 
@@ -71,8 +78,8 @@ def angle(v):
     return math.atan2(v[1], v[0])
 
 
-def get_angle(head, body, food):
-    d1 = angle(diff(head, body))
+def get_angle(head, velocity, food):
+    d1 = angle(velocity)
     d2 = angle(diff(food, head))
     d = d2 - d1
     return d
@@ -82,7 +89,7 @@ def get_command():
     snake = [x for x in snakes if x.player_id == player_id][0]
     food = latest_food(snake.segments[0])
     if food:
-        direction_diff = get_angle(snake.segments[0], snake.segments[1], food)
+        direction_diff = get_angle(snake.segments[0], snake.velocity, food)
 
         # logging.info('direction %s', direction_diff)
         if abs(direction_diff) < 0.1:
@@ -95,34 +102,61 @@ def get_command():
 
 
 def main():
-    global initializing, player_id, reading_map
+    global initializing, player_id, reading_map, tick, edge_mode, game_over
     while True:
         try:
             command = read_line()
         except EOFError:
             logging.info("program shutdowns")
             break
-        if command == "INIT BEGIN":
+        if command == "GAME OVER BEGIN":
+            game_over = True
+        elif command == "GAME OVER END":
+            game_over = False
+        elif game_over:
+            logging.info("game over: %s", command)
+        elif command == "INIT BEGIN":
             initializing = True
         elif command == "INIT END":
             initializing = False
-            print_line("username simple_ai")
+            print_line("username simple_ai protocol 2")
         elif command.startswith("player_id"):
             player_id = int(command.split()[1])
+        elif command.startswith("edge_mode"):
+            edge_mode = command.split()[1]
+        elif command.startswith("protocol_versions"):
+            if "2" not in command.split()[1:]:
+                logging.error("the game does not speak protocol 2: %s", command)
+        elif initializing:
+            # obstacles, and any other key that is not a number, are of no use to this bot
+            key, _, value = command.partition(" ")
+            try:
+                game_config[key] = float(value)
+            except ValueError:
+                logging.info("skipping %s", command)
         elif command == "MAP BEGIN":
             reading_map = True
             snakes.clear()
             foods.clear()
         elif command == "MAP END":
             reading_map = False
+        elif command.startswith("tick"):
+            tick = int(command.split()[1])
         elif command.startswith("snake"):
-            player_id, *segments = command.split()[1:]
-            snake = Snake(player_id)
-            snake.segments.extend([parse_pos(x) for x in segments])
+            # snake <id> velocity (x,y) radius r length n score n alive 0|1 username name body (x,y)...
+            words = command.split()
+            snake = Snake(int(words[1]))
+            body = words.index("body")
+            fields = dict(zip(words[2:body:2], words[3:body:2]))
+            snake.velocity = parse_pos(fields["velocity"])
+            snake.alive = fields["alive"] == "1"
+            snake.segments.extend([parse_pos(x) for x in words[body + 1:]])
             snakes.append(snake)
         elif command.startswith("food"):
             pos = command.split()[1]
             foods.append(parse_pos(pos))
+        elif command.startswith("kill"):
+            pass
         elif command == "REQUEST_ACTION":
             print_line(get_command())
             # more action
@@ -14,7 +14,11 @@ int main() {
     char buffer[1000];
     int initializing = 0;
     int player_id = -1;
+    unsigned long long tick = 0;
+    char edge_mode[16] = "wall";
+    float arena_width = 0, arena_height = 0, head_speed = 0, turn_rate = 0;
     int reading_map = 0;
+    int game_over = 0;
     struct Position snakes[10][100] = {};
     int snake_len[10] = {};
 
@@ -31,9 +35,23 @@ int main() {
             initializing = 1;
         } else if (strcmp(line, "INIT END") == 0) {
             initializing = 0;
-            printf("username c_ai\n");
+            printf("username c_ai protocol 1\n");
         } else if (strstr(line, "player_id") == line) {
             sscanf(line + strlen("player_id "), "%d", &player_id);
+        } else if (strcmp(line, "GAME OVER BEGIN") == 0) {
+            game_over = 1;
+        } else if (strcmp(line, "GAME OVER END") == 0) {
+            game_over = 0;
+        } else if (game_over) {
+            fprintf(stderr, "game over: %s\n", line);
+        } else if (strstr(line, "edge_mode") == line) {
+            sscanf(line + strlen("edge_mode "), "%15s", edge_mode);
+        } else if (initializing) {
+            // the rest of INIT: arena size, speeds, radii... as `key value`
+            sscanf(line, "arena_width %f", &arena_width);
+            sscanf(line, "arena_height %f", &arena_height);
+            sscanf(line, "head_speed %f", &head_speed);
+            sscanf(line, "turn_rate %f", &turn_rate);
         } else if (strcmp(line, "MAP BEGIN") == 0) {
             reading_map = 1;
             food_len = 0;
@@ -42,6 +60,8 @@ int main() {
             }
         } else if (strcmp(line, "MAP END") == 0) {
             reading_map = 0;
+        } else if (strstr(line, "tick") == line) {
+            sscanf(line + strlen("tick "), "%llu", &tick);
         } else if (strstr(line, "snake") == line) {
             int snake_id;
             int pos;
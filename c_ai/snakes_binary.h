@@ -0,0 +1,146 @@
+// Frames of protocol version 4, as sent by the game. Everything is little endian and the
+// structs have no padding, so on x86-64 and ARM they can be filled with a plain fread.
+//
+// Every frame is a `struct frame_header` followed by `length` bytes of payload.
+// The bot still answers with text lines: `username <name> protocol 4`, then
+// `turn_left`, `turn_right` or `straight` for every REQUEST_ACTION.
+#ifndef SNAKES_BINARY_H
+#define SNAKES_BINARY_H
+
+#include <stdint.h>
+
+#define TAG_INIT 1
+#define TAG_MAP 2
+#define TAG_REQUEST_ACTION 3
+#define TAG_GAME_OVER 4
+
+#define NAME_LENGTH 32
+
+#define EDGE_WALL 0
+#define EDGE_WRAP 1
+#define EDGE_BOUNCE 2
+
+#define REASON_TICK_LIMIT 0
+#define REASON_SCORE_TARGET 1
+#define REASON_LAST_SNAKE_STANDING 2
+
+#define FOOD_NORMAL 0
+#define FOOD_LARGE 1
+#define FOOD_SPEED_UP 2
+#define FOOD_SHRINK 3
+#define FOOD_GHOST 4
+
+#define CAUSE_WALL 0
+#define CAUSE_BODY 1
+#define CAUSE_HEAD_ON 2
+#define CAUSE_SELF_COLLISION 3
+#define CAUSE_OBSTACLE 4
+
+#define SHAPE_RECT 0
+#define SHAPE_CIRCLE 1
+#define SHAPE_POLYGON 2
+
+struct frame_header {
+    uint32_t tag;
+    // bytes of payload following the header
+    uint32_t length;
+};
+
+// payload of TAG_INIT, only sent when the manifest of the bot has `protocol = 4`:
+//   struct init
+//   uint32_t obstacle_count
+//   obstacle_count times: struct obstacle_header, then `value_count` floats
+struct init {
+    uint32_t protocol_version;
+    int32_t player_id;
+    uint32_t edge_mode;
+    float arena_width;
+    float arena_height;
+    float const_speed;
+    // units per second
+    float head_speed;
+    // radians per second
+    float turn_rate;
+    float tick_seconds;
+    float radius_snake;
+    float radius_food;
+    uint32_t response_deadline_ms;
+};
+
+// values of each shape:
+//   SHAPE_RECT: x, y of the centre, width, height
+//   SHAPE_CIRCLE: x, y of the centre, radius
+//   SHAPE_POLYGON: x, y of each corner, in order
+struct obstacle_header {
+    uint32_t shape;
+    uint32_t value_count;
+};
+
+struct position {
+    float x;
+    float y;
+};
+
+// payload of TAG_MAP:
+//   struct map_header
+//   snake_count times: struct snake_header, then `length` struct position, head first
+//   food_count times: struct food
+//   uint32_t kill_count, then kill_count struct kill
+struct map_header {
+    uint64_t tick;
+    uint32_t snake_count;
+    uint32_t food_count;
+};
+
+struct snake_header {
+    int32_t player_id;
+    uint32_t alive;
+    float velocity_x;
+    float velocity_y;
+    float radius;
+    // points by the scoring formula of the match
+    float score;
+    uint32_t length;
+    // NUL terminated
+    char username[NAME_LENGTH];
+};
+
+struct food {
+    float x;
+    float y;
+    // segments gained by eating it
+    uint32_t value;
+    // one of FOOD_*
+    uint32_t kind;
+};
+
+// a snake that died in the last tick
+struct kill {
+    int32_t victim;
+    // -1 for none
+    int32_t killer;
+    // one of CAUSE_*
+    uint32_t cause;
+};
+
+// TAG_REQUEST_ACTION has no payload
+
+// payload of TAG_GAME_OVER: struct game_over, then standing_count struct standing
+struct game_over {
+    uint32_t round;
+    uint32_t reason;
+    uint64_t tick;
+    // -1 for a draw
+    int32_t winner;
+    uint32_t standing_count;
+};
+
+struct standing {
+    uint32_t rank;
+    int32_t player_id;
+    float score;
+    uint32_t alive;
+    char username[NAME_LENGTH];
+};
+
+#endif
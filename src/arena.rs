@@ -0,0 +1,277 @@
+use crate::{ARENA_HEIGHT, ARENA_WIDTH};
+use anyhow::{Context, Result};
+use bevy::math::Vec2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A solid shape in the arena. A snake head touching one dies.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Obstacle {
+    /// Axis aligned, `x` and `y` being its centre
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+    },
+    /// Corners in order, either way round
+    Polygon {
+        points: Vec<[f32; 2]>,
+    },
+}
+impl Obstacle {
+    /// Distance from `p` to the obstacle, 0 inside it
+    pub fn distance(&self, p: Vec2) -> f32 {
+        match self {
+            Obstacle::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let d = (p - Vec2::new(*x, *y)).abs() - Vec2::new(*width, *height) / 2.0;
+                d.max(Vec2::ZERO).length()
+            }
+            Obstacle::Circle { x, y, radius } => {
+                ((p - Vec2::new(*x, *y)).length() - radius).max(0.0)
+            }
+            Obstacle::Polygon { points } => {
+                let corners: Vec<Vec2> = points.iter().map(|[x, y]| Vec2::new(*x, *y)).collect();
+                let mut inside = false;
+                let mut nearest = f32::INFINITY;
+                for (i, a) in corners.iter().enumerate() {
+                    let b = corners[(i + 1) % corners.len()];
+                    // even-odd rule, casting a ray towards +x
+                    if (a.y > p.y) != (b.y > p.y)
+                        && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                    let edge = b - *a;
+                    let t = ((p - *a).dot(edge) / edge.length_squared()).clamp(0.0, 1.0);
+                    nearest = nearest.min((*a + edge * t - p).length());
+                }
+                if inside {
+                    0.0
+                } else {
+                    nearest
+                }
+            }
+        }
+    }
+    /// Corners of the smallest axis aligned box around the obstacle
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        match self {
+            Obstacle::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let half = Vec2::new(*width, *height) / 2.0;
+                (Vec2::new(*x, *y) - half, Vec2::new(*x, *y) + half)
+            }
+            Obstacle::Circle { x, y, radius } => (
+                Vec2::new(x - radius, y - radius),
+                Vec2::new(x + radius, y + radius),
+            ),
+            Obstacle::Polygon { points } => points.iter().fold(
+                (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)),
+                |(min, max), [x, y]| (min.min(Vec2::new(*x, *y)), max.max(Vec2::new(*x, *y))),
+            ),
+        }
+    }
+}
+
+/// Round area the food spawner puts food in
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FoodZone {
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+}
+
+/// Layout of the arena, loaded from the map file of the match
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArenaMap {
+    pub obstacles: Vec<Obstacle>,
+    /// Snakes spawn at one of these, picked at random, or anywhere clear when there are none
+    pub spawn_points: Vec<[f32; 2]>,
+    /// Spawned food only lands in these when there are some
+    pub food_zones: Vec<FoodZone>,
+}
+impl ArenaMap {
+    /// JSON for a `.json` file, a text grid for anything else
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read map {}", path.display()))?;
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => serde_json::from_str(&text)
+                .with_context(|| format!("Could not parse map {}", path.display())),
+            _ => Self::parse_grid(&text)
+                .with_context(|| format!("Could not parse map {}", path.display())),
+        }
+    }
+    /// A grid of characters stretched over the whole arena, the first line at the top:
+    /// `#` is a wall, `S` a spawn point, `F` a food zone, `.` or a space nothing.
+    /// Lines starting with `;` are comments.
+    pub fn parse_grid(text: &str) -> Result<Self> {
+        let rows: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.starts_with(';'))
+            .map(|line| line.trim_end())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let columns = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        if columns == 0 {
+            anyhow::bail!("The grid is empty");
+        }
+        let cell = Vec2::new(
+            ARENA_WIDTH / columns as f32,
+            ARENA_HEIGHT / rows.len() as f32,
+        );
+        let centre = |column: usize, row: usize| {
+            Vec2::new(
+                (column as f32 + 0.5) * cell.x - ARENA_WIDTH / 2.0,
+                ARENA_HEIGHT / 2.0 - (row as f32 + 0.5) * cell.y,
+            )
+        };
+        let mut map = Self::default();
+        for (row, line) in rows.iter().enumerate() {
+            let chars: Vec<char> = line.chars().collect();
+            let mut column = 0;
+            while column < chars.len() {
+                match chars[column] {
+                    '#' => {
+                        // a run of walls is a single rectangle
+                        let start = column;
+                        while column + 1 < chars.len() && chars[column + 1] == '#' {
+                            column += 1;
+                        }
+                        let middle = (centre(start, row) + centre(column, row)) / 2.0;
+                        map.obstacles.push(Obstacle::Rect {
+                            x: middle.x,
+                            y: middle.y,
+                            width: (column - start + 1) as f32 * cell.x,
+                            height: cell.y,
+                        });
+                    }
+                    'S' => {
+                        let pos = centre(column, row);
+                        map.spawn_points.push([pos.x, pos.y]);
+                    }
+                    'F' => {
+                        let pos = centre(column, row);
+                        map.food_zones.push(FoodZone {
+                            x: pos.x,
+                            y: pos.y,
+                            radius: cell.min_element() / 2.0,
+                        });
+                    }
+                    '.' | ' ' => {}
+                    other => anyhow::bail!(
+                        "Unknown character {:?} at line {}, column {}",
+                        other,
+                        row + 1,
+                        column + 1
+                    ),
+                }
+                column += 1;
+            }
+        }
+        Ok(map)
+    }
+    /// Distance from `p` to the nearest obstacle
+    pub fn obstacle_distance(&self, p: Vec2) -> f32 {
+        self.obstacles
+            .iter()
+            .map(|obstacle| obstacle.distance(p))
+            .fold(f32::INFINITY, f32::min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(obstacle: &Obstacle) -> [f32; 4] {
+        match obstacle {
+            Obstacle::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => [*x, *y, *width, *height],
+            other => panic!("{:?} is not a rect", other),
+        }
+    }
+
+    #[test]
+    fn grid_cells_cover_the_arena() {
+        let map = ArenaMap::parse_grid("##..\n.S..\n; a comment\n..F.\n...#\n").unwrap();
+        let (w, h) = (ARENA_WIDTH / 4.0, ARENA_HEIGHT / 4.0);
+        let rects: Vec<[f32; 4]> = map.obstacles.iter().map(rect).collect();
+        assert_eq!(
+            rects,
+            [
+                // the two walls of the first line are a single rectangle
+                [-w, 1.5 * h, 2.0 * w, h],
+                [1.5 * w, -1.5 * h, w, h],
+            ]
+        );
+        assert_eq!(map.spawn_points, [[-0.5 * w, 0.5 * h]]);
+        assert_eq!(map.food_zones.len(), 1);
+        let zone = &map.food_zones[0];
+        assert_eq!(
+            [zone.x, zone.y, zone.radius],
+            [0.5 * w, -0.5 * h, w.min(h) / 2.0]
+        );
+    }
+
+    #[test]
+    fn grid_rejects_unknown_characters_and_empty_grids() {
+        let err = ArenaMap::parse_grid("..\n.x\n").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown character 'x' at line 2, column 2");
+        assert!(ArenaMap::parse_grid("; nothing but a comment\n\n").is_err());
+    }
+
+    #[test]
+    fn distance_is_zero_inside_and_to_the_nearest_edge_outside() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        let rect = Obstacle::Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 4.0,
+            height: 2.0,
+        };
+        assert_eq!(rect.distance(Vec2::new(1.0, 0.5)), 0.0);
+        assert!(close(rect.distance(Vec2::new(5.0, 0.0)), 3.0));
+        assert!(close(rect.distance(Vec2::new(5.0, 4.0)), 18f32.sqrt()));
+        let circle = Obstacle::Circle {
+            x: 1.0,
+            y: 1.0,
+            radius: 2.0,
+        };
+        assert_eq!(circle.distance(Vec2::new(2.0, 2.0)), 0.0);
+        assert!(close(circle.distance(Vec2::new(1.0, 6.0)), 3.0));
+        let triangle = Obstacle::Polygon {
+            points: vec![[0.0, 0.0], [4.0, 0.0], [0.0, 4.0]],
+        };
+        assert_eq!(triangle.distance(Vec2::new(1.0, 1.0)), 0.0);
+        assert!(close(triangle.distance(Vec2::new(-3.0, 0.0)), 3.0));
+        assert!(close(triangle.distance(Vec2::new(3.0, 3.0)), 2f32.sqrt()));
+    }
+}
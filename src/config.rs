@@ -0,0 +1,276 @@
+use crate::arena::ArenaMap;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Read when no `--config` flag is given, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "match.toml";
+
+/// What happens to a snake head reaching the border of the arena
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeMode {
+    /// The border is a solid wall, touching it kills the snake
+    #[default]
+    Wall,
+    /// The arena is a torus, leaving on one side enters from the opposite side
+    Wrap,
+    /// The head is reflected back into the arena
+    Bounce,
+}
+impl std::fmt::Display for EdgeMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EdgeMode::Wall => "wall",
+            EdgeMode::Wrap => "wrap",
+            EdgeMode::Bounce => "bounce",
+        })
+    }
+}
+
+/// Who dies when two heads meet
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadOn {
+    #[default]
+    BothDie,
+    /// The shorter snake dies, both do if they are as long
+    LongerWins,
+    /// One of them, picked by the match RNG
+    Random,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollisionRules {
+    /// A snake running into its own body dies
+    pub self_collision: bool,
+    /// Length of body behind the head, in arena units, that never counts as a self collision
+    pub self_collision_grace: f32,
+    pub head_on: HeadOn,
+}
+impl Default for CollisionRules {
+    fn default() -> Self {
+        Self {
+            self_collision: false,
+            self_collision_grace: 20.0,
+            head_on: HeadOn::BothDie,
+        }
+    }
+}
+
+/// Points of a player on the leaderboard: each weight times the matching count of its
+/// `PlayerScore`, added up
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreFormula {
+    pub food: f32,
+    pub kill: f32,
+    pub death: f32,
+    pub longest_length: f32,
+    /// Per second alive
+    pub survival: f32,
+}
+impl Default for ScoreFormula {
+    fn default() -> Self {
+        Self {
+            food: 1.0,
+            kill: 10.0,
+            death: -5.0,
+            longest_length: 0.0,
+            survival: 0.0,
+        }
+    }
+}
+
+/// Where the food spawner puts food
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FoodDistribution {
+    /// Anywhere in the arena
+    #[default]
+    Uniform,
+    /// Around `clusters` places picked at random at the start of each round
+    Clustered,
+    /// Around the fixed `hotspots`
+    Hotspots,
+}
+
+/// How the arena is kept supplied with food
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FoodSpawnPolicy {
+    /// Ticks between two spawns
+    pub interval_ticks: u64,
+    /// Food per 1000 square units of arena the spawner stops at
+    pub target_density: Option<f32>,
+    /// The spawner adds no food above this count. Food left by dead or boosting snakes is
+    /// added anyway.
+    pub max_count: Option<usize>,
+    /// Spawned food stays this far from snake heads, and off snake bodies
+    pub head_exclusion_radius: f32,
+    pub distribution: FoodDistribution,
+    /// Number of cluster centres of the clustered distribution
+    pub clusters: u32,
+    /// Centres of the hotspot distribution, as `[x, y]`
+    pub hotspots: Vec<[f32; 2]>,
+    /// Food lands at most this far from a cluster centre or hotspot
+    pub spread: f32,
+}
+impl Default for FoodSpawnPolicy {
+    fn default() -> Self {
+        Self {
+            interval_ticks: 60,
+            target_density: None,
+            max_count: Some(100),
+            head_exclusion_radius: 15.0,
+            distribution: FoodDistribution::Uniform,
+            clusters: 3,
+            hotspots: vec![],
+            spread: 10.0,
+        }
+    }
+}
+
+/// When a round is over. Any condition that is met ends it; with none set it runs forever.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndConditions {
+    /// Number of ticks a round lasts
+    pub tick_limit: Option<u64>,
+    /// The round ends as soon as a player made this many points by the scoring formula in it
+    pub score_target: Option<f32>,
+    /// Dead snakes are out of the round instead of respawning, and the round ends when at most
+    /// one snake is left
+    pub last_snake_standing: bool,
+}
+
+/// Settings of one match, loaded from a TOML file. Command line flags take precedence.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Seed of the simulation RNG. The same seed and the same AI inputs replay the same match.
+    /// A random seed is picked (and logged) when absent.
+    pub seed: Option<u64>,
+    pub edge_mode: EdgeMode,
+    /// Ticks between the end of the lobby and the start of a round
+    pub countdown_ticks: u64,
+    pub end: EndConditions,
+    pub collisions: CollisionRules,
+    pub scoring: ScoreFormula,
+    /// Every segment of a dead snake turns into a food pellet worth this many segments.
+    /// With 0 the body just disappears.
+    pub corpse_food_value: u32,
+    pub food_spawn: FoodSpawnPolicy,
+    /// Map file with the obstacles, spawn points and food zones, see `ArenaMap::load`
+    pub map: Option<PathBuf>,
+    /// Loaded from `map` by `from_args`
+    #[serde(skip)]
+    pub arena: ArenaMap,
+    /// Time an AI has to answer INIT, in milliseconds
+    pub init_timeout_ms: u64,
+    /// Time an AI has to answer REQUEST_ACTION, in milliseconds. A late answer counts as
+    /// `straight` and is dropped when it arrives. AIs are asked every tick, so a deadline
+    /// longer than `TICK` lets a slow AI drag the game below real time.
+    pub response_deadline_ms: u64,
+    /// An AI missing this many deadlines in a row is disqualified and killed
+    pub max_missed_deadlines: u32,
+    /// Start an AI again after it crashed or failed, waiting longer after every failure
+    pub restart_failed_ai: bool,
+    /// Wait before the first restart, in milliseconds. It doubles after every failure.
+    pub restart_backoff_ms: u64,
+    /// An AI that failed more often than this stays out of the match
+    pub max_restarts: u32,
+    /// AIs speaking the delta protocol get the whole world again after this many ticks
+    pub resync_ticks: u64,
+    /// What the AIs print on stderr goes to `<log_dir>/<match_name>/<player_id>-<username>.log`
+    pub log_dir: PathBuf,
+    /// Defaults to `match-<unix time>`
+    pub match_name: Option<String>,
+}
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            edge_mode: Default::default(),
+            countdown_ticks: 180,
+            end: Default::default(),
+            collisions: Default::default(),
+            scoring: Default::default(),
+            corpse_food_value: 2,
+            food_spawn: Default::default(),
+            map: None,
+            arena: Default::default(),
+            init_timeout_ms: 2000,
+            response_deadline_ms: 10,
+            max_missed_deadlines: 60,
+            restart_failed_ai: false,
+            restart_backoff_ms: 1000,
+            max_restarts: 5,
+            resync_ticks: 300,
+            log_dir: PathBuf::from("logs"),
+            match_name: None,
+        }
+    }
+}
+
+impl MatchConfig {
+    /// Whether dead snakes come back, or are out until the next round
+    pub fn respawn(&self) -> bool {
+        !self.end.last_snake_standing
+    }
+    /// Directory holding the AI logs of this match
+    pub fn match_log_dir(&self) -> PathBuf {
+        self.log_dir
+            .join(self.match_name.as_deref().unwrap_or("unnamed"))
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Could not parse config {}", path.display()))
+    }
+    /// Accepts `--config <file>`, `--seed <n>` and `--map <file>`
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config_file = None;
+        let mut seed = None;
+        let mut map = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("Missing value after {}", arg))
+            };
+            match arg.as_str() {
+                "--config" => config_file = Some(value()?),
+                "--seed" => {
+                    let v = value()?;
+                    seed = Some(v.parse().with_context(|| format!("Invalid seed {:?}", v))?);
+                }
+                "--map" => map = Some(PathBuf::from(value()?)),
+                _ => anyhow::bail!("Unknown argument {:?}", arg),
+            }
+        }
+        let mut config = match config_file {
+            Some(file) => Self::load(file)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::load(DEFAULT_CONFIG_FILE)?,
+            None => Self::default(),
+        };
+        if seed.is_some() {
+            config.seed = seed;
+        }
+        if map.is_some() {
+            config.map = map;
+        }
+        if let Some(map) = &config.map {
+            config.arena = ArenaMap::load(map)?;
+        }
+        config.match_name.get_or_insert_with(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            format!("match-{}", now.as_secs())
+        });
+        Ok(config)
+    }
+}
@@ -1,161 +1,539 @@
+use crate::config::MatchConfig;
+use crate::manifest::AiManifest;
+use crate::protocol::{self, Protocol, DEFAULT_VERSION, SUPPORTED_VERSIONS};
+use crate::simulation::MatchResults;
 use crate::{PlayerId, Position, SnakeWorld};
 use anyhow::{Context, Result};
 use bevy::log::*;
-use bevy::math::Vec3Swizzles;
-use std::ffi::OsStr;
-use std::io::{Read, Write};
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Copy, Clone)]
 pub enum MovementCommand {
     NoOps,
     TurnLeft,
     TurnRight,
+    /// Turns by this many radians, counter-clockwise if positive.
+    /// Clamped to what a snake can turn in a tick.
+    Steer(f32),
+    /// Turns towards a point of the arena, as far as a snake can in a tick
+    FaceTowards(Position),
+}
+/// What a player does in a tick
+#[derive(Debug, Copy, Clone)]
+pub struct PlayerAction {
+    pub movement: MovementCommand,
+    /// Moves faster, burning segments
+    pub boost: bool,
+}
+impl From<MovementCommand> for PlayerAction {
+    fn from(movement: MovementCommand) -> Self {
+        Self {
+            movement,
+            boost: false,
+        }
+    }
 }
 #[derive(Debug, Clone)]
 pub struct PlayerInfo {
     pub username: String,
     pub is_ai: bool,
+    /// Overrides the colour picked from the player id
+    pub color: Option<[u8; 3]>,
 }
 pub trait Controller: 'static + Send + Sync {
-    fn initialize(&mut self, player_id: PlayerId) -> Result<PlayerInfo>;
+    /// Identifies the controller in logs
+    fn name(&self) -> &str;
+    fn initialize(&mut self, player_id: PlayerId, config: &MatchConfig) -> Result<PlayerInfo>;
     fn feed_input(&mut self, world: &SnakeWorld) -> Result<()>;
-    fn get_output(&mut self) -> Result<MovementCommand>;
+    fn get_output(&mut self) -> Result<PlayerAction>;
+    /// Sent once when a round is over
+    fn game_over(&mut self, results: &MatchResults) -> Result<()>;
+    /// Shuts the controller down, reaping its process if it has one
+    fn stop(&mut self);
+    /// Starts a stopped or crashed controller again. `initialize` has to be called afterwards.
+    fn restart(&mut self) -> Result<()>;
+    /// Last lines the controller logged, oldest first
+    fn recent_log(&self) -> Vec<String> {
+        vec![]
+    }
+}
+/// Returned once an AI missed too many deadlines in a row. Its process is killed by then.
+#[derive(Debug)]
+pub struct Disqualified {
+    pub name: String,
+    pub missed_deadlines: u32,
+}
+impl std::fmt::Display for Disqualified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "AI {} is disqualified after missing {} deadlines in a row",
+            self.name, self.missed_deadlines
+        )
+    }
+}
+impl std::error::Error for Disqualified {}
+
+/// Longest line accepted from an AI, in bytes
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+/// An AI sent something that can't be read as a line of the protocol
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for ProtocolError {}
+
+/// Splits the byte stream coming from an AI into lines.
+/// A partial line is kept until its newline arrives.
+#[derive(Default)]
+struct LineFramer {
+    buf: Vec<u8>,
+    /// Inside a line that was too long, which is dropped up to its newline
+    skipping: bool,
+}
+impl LineFramer {
+    fn push(&mut self, data: &[u8]) -> Vec<Result<String, ProtocolError>> {
+        let mut lines = vec![];
+        for &byte in data {
+            if byte == b'\n' {
+                if self.skipping {
+                    self.skipping = false;
+                    continue;
+                }
+                let mut line = std::mem::take(&mut self.buf);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                lines.push(
+                    String::from_utf8(line)
+                        .map_err(|err| ProtocolError(format!("Invalid UTF-8 in line: {}", err))),
+                );
+            } else if self.skipping {
+                continue;
+            } else if self.buf.len() >= MAX_LINE_LENGTH {
+                self.buf.clear();
+                self.skipping = true;
+                lines.push(Err(ProtocolError(format!(
+                    "Line longer than {} bytes",
+                    MAX_LINE_LENGTH
+                ))));
+            } else {
+                self.buf.push(byte);
+            }
+        }
+        lines
+    }
+}
+
+/// Number of stderr lines of an AI kept for display
+pub const RECENT_LOG_LINES: usize = 12;
+
+/// What an AI prints on stderr. Every line is tagged with the player id and the tick,
+/// written to the log file of the AI and kept around for display.
+#[derive(Default)]
+pub struct AiLog {
+    player_id: Option<PlayerId>,
+    tick: u64,
+    file: Option<File>,
+    /// Lines printed before the log file could be opened
+    pending: Vec<String>,
+    recent: VecDeque<String>,
+}
+impl AiLog {
+    fn push(&mut self, line: &str) {
+        let line = match self.player_id {
+            Some(player_id) => format!("[player {} tick {}] {}", player_id.0, self.tick, line),
+            None => format!("[tick {}] {}", self.tick, line),
+        };
+        match &mut self.file {
+            Some(file) => {
+                let _ = std::writeln!(file, "{}", line);
+            }
+            None => self.pending.push(line.clone()),
+        }
+        self.recent.push_back(line);
+        if self.recent.len() > RECENT_LOG_LINES {
+            self.recent.pop_front();
+        }
+    }
+    fn open(&mut self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        for line in self.pending.drain(..) {
+            std::writeln!(file, "{}", line)?;
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+/// `<player_id>-<username>.log`, so that AIs sharing a username get their own file.
+/// Usernames come from the AIs, keep only what is safe in a file name.
+fn log_file_name(player_id: PlayerId, username: &str) -> String {
+    let name: String = username
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("{}-{}.log", player_id.0, name)
+}
+
+/// Buffers a message and hands it to a writer thread on `flush`,
+/// so an AI that stops reading its stdin can't block the game
+struct PipeWriter {
+    buf: Vec<u8>,
+    tx: Sender<Vec<u8>>,
+}
+impl Write for PipeWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .send(std::mem::take(&mut self.buf))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Program exited"))
+    }
 }
-#[allow(dead_code)]
+
 pub struct StdioController {
     name: String,
+    /// Kept to start the AI again after a crash
+    manifest: AiManifest,
     child: Child,
-    stdin: ChildStdin,
-    stdout: ChildStdout,
-    // read_buf: Vec<u8>
-}
-macro_rules! writeln {
-    ($dst:expr, $($arg:tt)*) => {{
-        // info!("writing {:?}", format!($($arg) *));
-        std::writeln!($dst, $($arg)*)
-    }};
-}
-
-fn try_open_file(file: impl AsRef<OsStr>) -> std::io::Result<Child> {
-    let file = file.as_ref().to_str().unwrap();
-    let args;
-    if file.ends_with(".py") {
-        args = vec!["python3", file];
-    } else {
-        args = vec![file.as_ref()];
-    }
-    let process = Command::new(&args[0])
-        .args(&args[1..])
+    stdin: PipeWriter,
+    /// Picked by the AI in its reply to INIT
+    protocol: Box<dyn Protocol>,
+    /// Filled by a reader thread, so that waiting for a reply can time out
+    stdout: Mutex<Receiver<Result<String, ProtocolError>>>,
+    log: Arc<Mutex<AiLog>>,
+    init_timeout: Duration,
+    deadline: Duration,
+    max_missed_deadlines: u32,
+    /// Replies still owed for requests that already timed out. They are dropped when they arrive.
+    late_replies: u32,
+    missed_deadlines: u32,
+    missed_in_a_row: u32,
+}
+fn spawn_process(manifest: &AiManifest) -> std::io::Result<Child> {
+    let mut command = Command::new(&manifest.command);
+    command
+        .args(&manifest.args)
+        .envs(&manifest.env)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        // .stderr(Stdio::piped())
-        .spawn()?;
+        .stderr(Stdio::piped());
+    if let Some(dir) = &manifest.working_dir {
+        command.current_dir(dir);
+    }
+    let process = command.spawn()?;
     info!("Spawned process {}", process.id());
     Ok(process)
 }
 
+/// Channels to the pipes of an AI process, each served by its own thread
+fn connect_pipes(
+    child: &mut Child,
+    log: Arc<Mutex<AiLog>>,
+) -> (PipeWriter, Receiver<Result<String, ProtocolError>>) {
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let (stdin_tx, stdin_rx) = channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        for message in stdin_rx {
+            if stdin.write_all(&message).is_err() {
+                break;
+            }
+        }
+    });
+    let (stdout_tx, stdout_rx) = channel();
+    std::thread::spawn(move || {
+        let mut framer = LineFramer::default();
+        let mut chunk = [0u8; 1000];
+        loop {
+            let len = match stdout.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            for line in framer.push(&chunk[..len]) {
+                if stdout_tx.send(line).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    let stderr = child.stderr.take().unwrap();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).split(b'\n') {
+            match line {
+                Ok(line) => log.lock().unwrap().push(&String::from_utf8_lossy(&line)),
+                Err(_) => break,
+            }
+        }
+    });
+    let stdin = PipeWriter {
+        buf: vec![],
+        tx: stdin_tx,
+    };
+    (stdin, stdout_rx)
+}
+
 impl StdioController {
-    pub fn new(file: impl AsRef<OsStr>) -> Result<Self> {
-        info!("Loading AI {}", file.as_ref().to_str().unwrap());
-        let mut child = try_open_file(file.as_ref())?;
-        let stdin = child.stdin.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
-        // let mut stderr = child.stderr.take().unwrap();
-        // std::thread::spawn(move || loop {
-        //     let mut buf = [0u8; 128];
-        //     let len = stderr.read(&mut buf).unwrap();
-        //     if len == 0 {
-        //         break;
-        //     }
-        //     info!(
-        //         "Read from stderr {}",
-        //         std::str::from_utf8(&buf[..len]).unwrap()
-        //     );
-        // });
+    pub fn new(manifest: AiManifest) -> Result<Self> {
+        info!("Loading AI {}", manifest.source.display());
+        let mut child = spawn_process(&manifest)?;
+        let log = Arc::new(Mutex::new(AiLog::default()));
+        let (stdin, stdout) = connect_pipes(&mut child, log.clone());
+        let defaults = MatchConfig::default();
         Ok(Self {
-            name: file.as_ref().to_str().unwrap().to_owned(),
+            name: manifest.source.to_str().unwrap().to_owned(),
+            manifest,
             child,
             stdin,
-            stdout,
-            // read_buf: vec![]
+            protocol: protocol::protocol(DEFAULT_VERSION, &defaults).unwrap(),
+            stdout: Mutex::new(stdout),
+            log,
+            init_timeout: Duration::from_millis(defaults.init_timeout_ms),
+            deadline: Duration::from_millis(defaults.response_deadline_ms),
+            max_missed_deadlines: defaults.max_missed_deadlines,
+            late_replies: 0,
+            missed_deadlines: 0,
+            missed_in_a_row: 0,
         })
     }
-    fn read_line(&mut self) -> anyhow::Result<String> {
-        let mut chunk = [0u8; 1000];
-        let len = self.stdout.read(&mut chunk)?;
-        let line = String::from_utf8(chunk[..len].to_owned()).unwrap();
-        if line.is_empty() {
-            anyhow::bail!("Program exited");
+    /// `None` if no line arrived before `deadline`.
+    /// The inner error stands for a line that was not valid.
+    fn read_line(
+        &mut self,
+        deadline: Instant,
+    ) -> anyhow::Result<Option<Result<String, ProtocolError>>> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.stdout.get_mut().unwrap().recv_timeout(timeout) {
+            Ok(line) => Ok(Some(line)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("Program exited"),
         }
-        Ok(line)
     }
-    pub fn parse_info(&mut self) -> anyhow::Result<PlayerInfo> {
+    /// Total number of requests the AI did not answer in time
+    pub fn missed_deadlines(&self) -> u32 {
+        self.missed_deadlines
+    }
+    fn disqualify(&mut self) -> anyhow::Error {
+        warn!("Killing AI {}", self.name);
+        self.stop();
+        Disqualified {
+            name: self.name.clone(),
+            missed_deadlines: self.missed_in_a_row,
+        }
+        .into()
+    }
+    pub fn parse_info(&mut self, line: &str, config: &MatchConfig) -> anyhow::Result<PlayerInfo> {
         info!("Parsing player info for AI {}", self.name);
-        let line = self.read_line()?;
-        let mut spt = line.split(" ");
-        let mut info = PlayerInfo {
-            username: "".to_string(),
+        let hello = self.protocol.parse_hello(line)?;
+        if let Some(version) = hello.protocol {
+            self.protocol = Self::protocol(version, config)?;
+        }
+        let info = PlayerInfo {
+            username: self.manifest.name.clone().unwrap_or(hello.username),
             is_ai: true,
+            color: self.manifest.color,
         };
-        if spt.next() == Some("username") {
-            let username = spt.next().context("Could not find username")?.trim();
-            info.username = username.to_owned();
-        } else {
-            anyhow::bail!("You must begin with username");
-        }
-        if info.username.is_empty() {
-            anyhow::bail!("Could not leave username empty");
-        }
-        info!("PlayerInfo parsed for AI {}: {:?}", self.name, info);
+        info!(
+            "PlayerInfo parsed for AI {}: {:?}, protocol version {}",
+            self.name,
+            info,
+            self.protocol.version()
+        );
         Ok(info)
     }
-    pub fn parse_action(&mut self) -> anyhow::Result<MovementCommand> {
-        let line = self.read_line()?;
-        let mut spt = line.split(" ");
-        let cmd = spt.next().map(|x| x.trim());
-        match cmd {
-            Some("turn_left") => Ok(MovementCommand::TurnLeft),
-            Some("turn_right") => Ok(MovementCommand::TurnRight),
-            Some("straight") => Ok(MovementCommand::NoOps),
-            Some(x) => {
-                anyhow::bail!("Does not recognize command {:?}", x)
-            }
-            None => {
-                anyhow::bail!("You must not leave an empty line")
-            }
-        }
+    fn protocol(version: u32, config: &MatchConfig) -> anyhow::Result<Box<dyn Protocol>> {
+        protocol::protocol(version, config).with_context(|| {
+            format!(
+                "Protocol version {} is not supported, only {:?}",
+                version, SUPPORTED_VERSIONS
+            )
+        })
+    }
+    pub fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        self.protocol.parse_action(line)
     }
 }
 
 impl Controller for StdioController {
-    fn initialize(&mut self, player_id: PlayerId) -> Result<PlayerInfo> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn initialize(&mut self, player_id: PlayerId, config: &MatchConfig) -> Result<PlayerInfo> {
         info!("Initializing AI {}", self.name);
-        writeln!(self.stdin, "INIT BEGIN")?;
-        writeln!(self.stdin, "player_id {}", player_id.0)?;
-        writeln!(self.stdin, "INIT END")?;
-        std::thread::sleep(std::time::Duration::from_millis(20));
-        self.parse_info()
+        self.protocol = Self::protocol(self.manifest.protocol.unwrap_or(DEFAULT_VERSION), config)?;
+        self.protocol
+            .write_init(&mut self.stdin, player_id, config)?;
+        self.stdin.flush()?;
+        self.init_timeout = Duration::from_millis(config.init_timeout_ms);
+        self.deadline = Duration::from_millis(config.response_deadline_ms);
+        self.max_missed_deadlines = config.max_missed_deadlines;
+        self.log.lock().unwrap().player_id = Some(player_id);
+        let line = self
+            .read_line(Instant::now() + self.init_timeout)?
+            .with_context(|| format!("AI {} did not answer INIT in time", self.name))?;
+        let info = self.parse_info(&line?, config)?;
+        let path = config
+            .match_log_dir()
+            .join(log_file_name(player_id, &info.username));
+        if let Err(err) = self.log.lock().unwrap().open(&path) {
+            warn!(
+                "Could not open log {} of AI {}: {}",
+                path.display(),
+                self.name,
+                err
+            );
+        }
+        Ok(info)
     }
 
     fn feed_input(&mut self, world: &SnakeWorld) -> Result<()> {
-        writeln!(self.stdin, "MAP BEGIN")?;
-        for snake in world.snakes.values() {
-            write!(self.stdin, "snake {}", snake.player_id.0)?;
-            for node in snake.body.values() {
-                write!(self.stdin, " {}", Position(node.trans.translation.xy()))?;
+        self.log.lock().unwrap().tick = world.tick;
+        self.protocol.write_map(&mut self.stdin, world)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn get_output(&mut self) -> Result<PlayerAction> {
+        self.protocol.write_request(&mut self.stdin)?;
+        self.stdin.flush()?;
+        let deadline = Instant::now() + self.deadline;
+        loop {
+            match self.read_line(deadline)? {
+                Some(_) if self.late_replies > 0 => {
+                    // answer to a request that already timed out
+                    self.late_replies -= 1;
+                }
+                Some(line) => {
+                    self.missed_in_a_row = 0;
+                    return self.parse_action(&line?);
+                }
+                None => {
+                    self.late_replies += 1;
+                    self.missed_deadlines += 1;
+                    self.missed_in_a_row += 1;
+                    warn!(
+                        "AI {} missed its deadline ({} in a row, {} in total)",
+                        self.name, self.missed_in_a_row, self.missed_deadlines
+                    );
+                    if self.missed_in_a_row >= self.max_missed_deadlines {
+                        return Err(self.disqualify());
+                    }
+                    return Ok(MovementCommand::NoOps.into());
+                }
             }
-            writeln!(self.stdin, "")?;
         }
-        for food in &world.foods {
-            writeln!(self.stdin, "food {}", food.pos)?;
-        }
-        writeln!(self.stdin, "MAP END")?;
+    }
+
+    fn game_over(&mut self, results: &MatchResults) -> Result<()> {
+        self.protocol.write_game_over(&mut self.stdin, results)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    fn recent_log(&self) -> Vec<String> {
+        self.log.lock().unwrap().recent.iter().cloned().collect()
+    }
+    fn restart(&mut self) -> Result<()> {
+        self.stop();
+        info!("Restarting AI {}", self.name);
+        let mut child = spawn_process(&self.manifest)?;
+        let (stdin, stdout) = connect_pipes(&mut child, self.log.clone());
+        self.child = child;
+        self.stdin = stdin;
+        self.stdout = Mutex::new(stdout);
+        self.late_replies = 0;
+        self.missed_in_a_row = 0;
         Ok(())
     }
+}
+
+impl Drop for StdioController {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok_lines(results: Vec<Result<String, ProtocolError>>) -> Vec<String> {
+        results.into_iter().map(|line| line.unwrap()).collect()
+    }
+
+    #[test]
+    fn a_line_split_over_reads_is_joined() {
+        let mut framer = LineFramer::default();
+        assert!(framer.push(b"turn_").is_empty());
+        assert!(framer.push(b"le").is_empty());
+        assert_eq!(ok_lines(framer.push(b"ft\nstr")), vec!["turn_left"]);
+        assert_eq!(ok_lines(framer.push(b"aight\n")), vec!["straight"]);
+    }
+
+    #[test]
+    fn lines_in_one_read_are_split() {
+        let mut framer = LineFramer::default();
+        assert_eq!(
+            ok_lines(framer.push(b"turn_left\r\nturn_right\n\nstraight\n")),
+            vec!["turn_left", "turn_right", "", "straight"]
+        );
+    }
+
+    #[test]
+    fn bad_utf8_fails_only_its_line() {
+        let mut framer = LineFramer::default();
+        let lines = framer.push(b"turn_\xff\xfeleft\nstraight\n");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].is_err());
+        assert_eq!(lines[1].as_deref().unwrap(), "straight");
+    }
+
+    #[test]
+    fn an_overlong_line_is_dropped_up_to_its_newline() {
+        let mut framer = LineFramer::default();
+        let long = vec![b'a'; MAX_LINE_LENGTH + 10];
+        let lines = framer.push(&long);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].is_err());
+        // the rest of the long line is skipped, the next one comes through
+        assert!(framer.push(b"aaaa").is_empty());
+        assert_eq!(ok_lines(framer.push(b"aa\nturn_left\n")), vec!["turn_left"]);
+    }
 
-    fn get_output(&mut self) -> Result<MovementCommand> {
-        writeln!(self.stdin, "REQUEST_ACTION")?;
-        self.parse_action()
+    #[test]
+    fn a_line_of_exactly_the_maximum_length_is_kept() {
+        let mut framer = LineFramer::default();
+        let mut data = vec![b'a'; MAX_LINE_LENGTH];
+        data.push(b'\n');
+        assert_eq!(ok_lines(framer.push(&data))[0].len(), MAX_LINE_LENGTH);
     }
 }
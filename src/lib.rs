@@ -1,8 +1,15 @@
+pub mod arena;
+pub mod config;
 pub mod controller;
+pub mod manifest;
+pub mod protocol;
+pub mod score;
+pub mod simulation;
 
 use crate::controller::PlayerInfo;
+use crate::simulation::{KillEvent, SimRng};
 use bevy::prelude::*;
-use rand::random;
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::fmt::Formatter;
 
@@ -15,12 +22,59 @@ pub struct SnakeHead;
 pub struct SnakeSegment(pub i32);
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Food;
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct FoodId(pub u64);
+/// What eating a food does, besides growing the snake by its value
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FoodKind {
+    Normal,
+    /// Bigger, and worth several segments
+    Large,
+    /// Moves the snake as if boosting for a while, without burning segments
+    SpeedUp,
+    /// Takes segments away, never below the starting length
+    Shrink,
+    /// The snake passes through snakes, and they through it, for a while
+    Ghost,
+}
+impl FoodKind {
+    pub const ALL: [FoodKind; 5] = [
+        FoodKind::Normal,
+        FoodKind::Large,
+        FoodKind::SpeedUp,
+        FoodKind::Shrink,
+        FoodKind::Ghost,
+    ];
+    pub fn color(&self) -> Color {
+        match self {
+            FoodKind::Normal => Color::rgb(0.8, 0.1, 0.1),
+            FoodKind::Large => Color::rgb(0.95, 0.55, 0.1),
+            FoodKind::SpeedUp => Color::rgb(0.95, 0.9, 0.2),
+            FoodKind::Shrink => Color::rgb(0.55, 0.2, 0.75),
+            FoodKind::Ghost => Color::rgb(0.7, 0.85, 0.95),
+        }
+    }
+}
+impl std::fmt::Display for FoodKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FoodKind::Normal => "normal",
+            FoodKind::Large => "large",
+            FoodKind::SpeedUp => "speed_up",
+            FoodKind::Shrink => "shrink",
+            FoodKind::Ghost => "ghost",
+        })
+    }
+}
 
 pub struct Materials {
     pub colors: Vec<Color>,
     pub head_material: Vec<Handle<ColorMaterial>>,
     pub segment_material: Handle<ColorMaterial>,
-    pub food_material: Handle<ColorMaterial>,
+    /// One for each of `FoodKind::ALL`, in that order
+    pub food_materials: Vec<Handle<ColorMaterial>>,
+    pub wall_material: Handle<ColorMaterial>,
 }
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
@@ -34,18 +88,18 @@ impl std::fmt::Display for Position {
     }
 }
 impl Position {
-    pub fn random(limit_x: f32, limit_y: f32) -> Self {
+    pub fn random(rng: &mut SimRng, limit_x: f32, limit_y: f32) -> Self {
         Self(Vec2::new(
-            (random::<f32>() - 0.5) * limit_x,
-            (random::<f32>() - 0.5) * limit_y,
+            (rng.next_f32() - 0.5) * limit_x,
+            (rng.next_f32() - 0.5) * limit_y,
         ))
     }
 }
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Velocity(pub Vec2);
 impl Velocity {
-    pub fn random(abs: f32) -> Self {
-        let angle = std::f32::consts::PI * 2.0 * random::<f32>();
+    pub fn random(rng: &mut SimRng, abs: f32) -> Self {
+        let angle = std::f32::consts::PI * 2.0 * rng.next_f32();
         Self(Vec2::new(abs.clone() * angle.cos(), abs * angle.sin()))
     }
 }
@@ -106,30 +160,40 @@ pub fn spawn_snake_segment(
         .insert(player)
         .id()
 }
-pub fn spawn_snake_with_nodes(
+pub fn spawn_food(
     commands: &mut Commands,
-    player: PlayerId,
+    id: FoodId,
+    kind: FoodKind,
     pos: Position,
-    vel: Velocity,
-    nodes: i32,
+    radius: Radius,
     materials: &Materials,
 ) -> Entity {
-    let head = spawn_snake_head(commands, player, pos, vel, materials);
-    for i in 1..=nodes {
-        spawn_snake_segment(commands, i, player, pos, materials);
-    }
-    head
-}
-pub fn spawn_food(commands: &mut Commands, pos: Position, materials: &Materials) -> Entity {
     commands
         .spawn_bundle(SpriteBundle {
-            material: materials.food_material.clone(),
-            sprite: Sprite::new(Vec2::new(GRID_SIZE / 3.0, GRID_SIZE / 3.0)),
+            material: materials.food_materials[kind as usize].clone(),
+            sprite: Sprite::new(Vec2::new(2.0 * radius.0, 2.0 * radius.0)),
             transform: Transform::from_xyz(pos.0.x.clone(), pos.0.y.clone(), 0.0),
             ..Default::default()
         })
         .insert(Food)
-        .insert(Radius(GRID_SIZE / 6.0))
+        .insert(id)
+        .insert(kind)
+        .insert(radius)
+        .id()
+}
+pub fn spawn_wall(
+    commands: &mut Commands,
+    center: Position,
+    size: Vec2,
+    materials: &Materials,
+) -> Entity {
+    commands
+        .spawn_bundle(SpriteBundle {
+            material: materials.wall_material.clone(),
+            sprite: Sprite::new(size),
+            transform: Transform::from_xyz(center.0.x, center.0.y, 0.0),
+            ..Default::default()
+        })
         .id()
 }
 #[derive(Default)]
@@ -144,6 +208,10 @@ pub struct SnakeBody<T> {
     pub player_info: Option<PlayerInfo>,
     pub head_speed: Option<Velocity>,
     pub head_radius: Option<Radius>,
+    /// Points of the player by the scoring formula, not the length of the snake
+    pub score: f32,
+    /// False once the snake is out of the round
+    pub alive: bool,
     pub body: BTreeMap<i32, SnakeNode<T>>,
 }
 impl<T> Default for SnakeBody<T> {
@@ -153,16 +221,26 @@ impl<T> Default for SnakeBody<T> {
             player_info: None,
             head_speed: None,
             head_radius: None,
+            score: 0.0,
+            alive: true,
             body: Default::default(),
         }
     }
 }
 
 pub struct FoodBody {
+    pub id: FoodId,
+    pub kind: FoodKind,
     pub pos: Position,
+    /// Segments gained by eating it
+    pub value: u32,
 }
 #[derive(Default)]
-pub struct SnakeWorld<'a> {
+pub struct SnakeWorld {
+    /// Number of ticks simulated so far
+    pub tick: u64,
     pub foods: Vec<FoodBody>,
-    pub snakes: BTreeMap<PlayerId, SnakeBody<&'a Transform>>,
+    pub snakes: BTreeMap<PlayerId, SnakeBody<Position>>,
+    /// Deaths of the last tick
+    pub kills: Vec<KillEvent>,
 }
@@ -1,39 +1,32 @@
 use anyhow::Context;
 use anyhow::Result;
+use bevy::core::FixedTimestep;
 use bevy::diagnostic::LogDiagnosticsPlugin;
 use bevy::ecs::system::EntityCommands;
 use bevy::input::system::exit_on_esc_system;
 #[allow(unused_imports)]
 use bevy::log::*;
-use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
-use bevy::utils::HashMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
-use std::time::Duration;
-use the_snakes::controller::{Controller, MovementCommand, PlayerInfo, StdioController};
+use std::time::{Duration, Instant};
+use the_snakes::arena::Obstacle;
+use the_snakes::config::MatchConfig;
+use the_snakes::controller::{
+    Controller, Disqualified, MovementCommand, PlayerAction, PlayerInfo, ProtocolError,
+    StdioController,
+};
+use the_snakes::manifest::AiManifest;
+use the_snakes::simulation::{MatchPhase, Simulation};
 use the_snakes::{
-    spawn_food, spawn_snake_segment, spawn_snake_with_nodes, Food, FoodBody, Materials, PlayerId,
-    Position, Radius, SnakeBody, SnakeHead, SnakeNode, SnakeSegment, SnakeWorld, Velocity,
-    ARENA_HEIGHT, ARENA_WIDTH, CONST_SPEED, TICK,
+    spawn_food, spawn_snake_head, spawn_snake_segment, spawn_wall, Food, FoodId, FoodKind,
+    Materials, PlayerId, Position, SnakeComponent, SnakeHead, SnakeSegment, SnakeWorld,
+    ARENA_HEIGHT, ARENA_WIDTH, TICK,
 };
 
-pub struct SnakeMoveTimer(pub Timer);
-impl Default for SnakeMoveTimer {
-    fn default() -> Self {
-        Self(Timer::new(
-            Duration::from_millis((TICK * 1000.0) as _),
-            true,
-        ))
-    }
-}
+/// Runs once every `TICK` regardless of the frame rate. All gameplay systems live here.
+const FIXED_UPDATE: &str = "fixed_update";
 
-pub struct FoodSpawnTimer(pub Timer);
-impl Default for FoodSpawnTimer {
-    fn default() -> Self {
-        Self(Timer::new(Duration::from_millis(1000), true))
-    }
-}
 fn setup(mut commands: Commands, mut materials: ResMut<Assets<ColorMaterial>>) {
     commands.spawn_bundle(OrthographicCameraBundle::new_2d());
     let colors = vec![
@@ -64,12 +57,77 @@ fn setup(mut commands: Commands, mut materials: ResMut<Assets<ColorMaterial>>) {
             .map(|x| materials.add(x.into()))
             .collect(),
         segment_material: materials.add(Color::rgb(0.4, 0.4, 0.4).into()),
-        food_material: materials.add(Color::rgb(0.8, 0.1, 0.1).into()),
+        food_materials: FoodKind::ALL
+            .iter()
+            .map(|kind| materials.add(kind.color().into()))
+            .collect(),
+        wall_material: materials.add(Color::rgb(0.3, 0.3, 0.35).into()),
     });
 }
+/// Obstacles of the map as wall sprites. Rectangles are a single sprite, other shapes are
+/// filled with small squares.
+fn draw_obstacles(mut commands: Commands, materials: Res<Materials>, sim: Res<Simulation>) {
+    const CELL: f32 = 1.0;
+    for obstacle in &sim.config.arena.obstacles {
+        if let Obstacle::Rect {
+            x,
+            y,
+            width,
+            height,
+        } = obstacle
+        {
+            let center = Position(Vec2::new(*x, *y));
+            spawn_wall(
+                &mut commands,
+                center,
+                Vec2::new(*width, *height),
+                &materials,
+            );
+            continue;
+        }
+        let (min, max) = obstacle.bounds();
+        let mut y = min.y + CELL / 2.0;
+        while y < max.y {
+            let mut x = min.x + CELL / 2.0;
+            while x < max.x {
+                if obstacle.distance(Vec2::new(x, y)) == 0.0 {
+                    let center = Position(Vec2::new(x, y));
+                    spawn_wall(&mut commands, center, Vec2::splat(CELL), &materials);
+                }
+                x += CELL;
+            }
+            y += CELL;
+        }
+    }
+}
+fn draw_arena_border(mut commands: Commands, materials: Res<Materials>) {
+    const THICKNESS: f32 = 1.0;
+    let (half_x, half_y) = (ARENA_WIDTH / 2.0, ARENA_HEIGHT / 2.0);
+    let horizontal = Vec2::new(ARENA_WIDTH + 2.0 * THICKNESS, THICKNESS);
+    let vertical = Vec2::new(THICKNESS, ARENA_HEIGHT + 2.0 * THICKNESS);
+    for (center, size) in [
+        (Vec2::new(0.0, half_y + THICKNESS / 2.0), horizontal),
+        (Vec2::new(0.0, -half_y - THICKNESS / 2.0), horizontal),
+        (Vec2::new(half_x + THICKNESS / 2.0, 0.0), vertical),
+        (Vec2::new(-half_x - THICKNESS / 2.0, 0.0), vertical),
+    ]
+    .iter()
+    {
+        spawn_wall(&mut commands, Position(*center), *size, &materials);
+    }
+}
+struct AiSlot {
+    controller: Box<dyn Controller>,
+    /// False while the AI is out of the game after a failure
+    playing: bool,
+    failures: u32,
+    /// When a failed AI is started again. `None` keeps it out for good.
+    retry_at: Option<Instant>,
+}
 #[derive(Default)]
 struct AiManager {
-    ais: HashMap<PlayerId, Box<dyn Controller>>,
+    // ordered, so that snakes are spawned in the same order for a given seed
+    ais: BTreeMap<PlayerId, AiSlot>,
 }
 
 impl AiManager {
@@ -80,61 +138,135 @@ impl AiManager {
                 path.as_ref().to_str().unwrap()
             )
         })?;
+        let mut paths = dir
+            .map(|f| f.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // read_dir order depends on the file system, but player ids must be reproducible
+        paths.sort();
         let mut player_id = 1;
-        for f in dir {
-            let entry = f?;
-            let controller = StdioController::new(entry.path())?;
-            self.ais
-                .insert(PlayerId(player_id.clone()), Box::new(controller));
+        for path in paths {
+            let manifest = match AiManifest::discover(&path) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    warn!("Skipping {}: {:#}", path.display(), err);
+                    continue;
+                }
+            };
+            match StdioController::new(manifest) {
+                Ok(controller) => {
+                    let slot = AiSlot {
+                        controller: Box::new(controller),
+                        playing: false,
+                        failures: 0,
+                        retry_at: None,
+                    };
+                    self.ais.insert(PlayerId(player_id), slot);
+                }
+                Err(err) => error!("Could not load AI {}: {:?}", path.display(), err),
+            }
             player_id += 1;
         }
         Ok(())
     }
-    fn initialize_all_ai(
-        &mut self,
-        command: &mut Commands,
-        materials: &Materials,
-        registry: &mut PlayerInfoRegistry,
-    ) -> Result<()> {
-        for (k, v) in self.ais.iter_mut() {
-            let info: PlayerInfo = v.initialize(*k)?;
-            assert_eq!(info.is_ai, true);
-            spawn_snake_with_nodes(
-                command,
-                *k,
-                Position::random(ARENA_WIDTH, ARENA_HEIGHT),
-                Velocity::random(CONST_SPEED),
-                3,
-                materials,
-            );
-            registry.player_infos.insert(*k, info);
+    fn initialize_all_ai(&mut self, sim: &mut Simulation) {
+        let ids: Vec<PlayerId> = self.ais.keys().copied().collect();
+        for id in ids {
+            self.initialize_ai(id, sim);
         }
-        Ok(())
     }
-}
-#[derive(Default)]
-struct PlayerInfoRegistry {
-    pub player_infos: BTreeMap<PlayerId, PlayerInfo>,
+    fn initialize_ai(&mut self, id: PlayerId, sim: &mut Simulation) {
+        let slot = self.ais.get_mut(&id).unwrap();
+        match slot.controller.initialize(id, &sim.config) {
+            Ok(info) => {
+                assert_eq!(info.is_ai, true);
+                slot.playing = true;
+                sim.add_player(id, info);
+            }
+            Err(err) => self.fail(id, err, sim),
+        }
+    }
+    /// Takes a crashed or misbehaving AI and its snake out of the game,
+    /// and schedules a restart if the match allows it
+    fn fail(&mut self, id: PlayerId, err: anyhow::Error, sim: &mut Simulation) {
+        let slot = self.ais.get_mut(&id).unwrap();
+        error!(
+            "AI {} of player {} failed: {:?}",
+            slot.controller.name(),
+            id.0,
+            err
+        );
+        slot.controller.stop();
+        slot.playing = false;
+        slot.failures += 1;
+        sim.remove_player(id);
+        let config = &sim.config;
+        slot.retry_at = if config.restart_failed_ai
+            && slot.failures <= config.max_restarts
+            && !err.is::<Disqualified>()
+        {
+            let backoff = config
+                .restart_backoff_ms
+                .saturating_mul(2u64.saturating_pow(slot.failures - 1));
+            info!("Restarting AI {} in {} ms", slot.controller.name(), backoff);
+            Some(Instant::now() + Duration::from_millis(backoff))
+        } else {
+            None
+        };
+    }
+    fn restart_failed_ai(&mut self, sim: &mut Simulation) {
+        let now = Instant::now();
+        let due: Vec<PlayerId> = self
+            .ais
+            .iter()
+            .filter(|(_, slot)| matches!(slot.retry_at, Some(at) if !slot.playing && at <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in due {
+            let slot = self.ais.get_mut(&id).unwrap();
+            slot.retry_at = None;
+            match slot.controller.restart() {
+                Ok(()) => self.initialize_ai(id, sim),
+                Err(err) => self.fail(id, err, sim),
+            }
+        }
+    }
+    /// Sends the same snapshot to every AI and waits for all the replies at once,
+    /// so a tick takes about as long as the slowest AI
+    fn query_all_ai(&mut self, world: &SnakeWorld) -> Vec<(PlayerId, Result<PlayerAction>)> {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .ais
+                .iter_mut()
+                .filter(|(_, slot)| slot.playing)
+                .map(|(id, slot)| {
+                    let ai = &mut slot.controller;
+                    let handle = scope.spawn(move || {
+                        ai.feed_input(world)?;
+                        ai.get_output()
+                    });
+                    (*id, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(id, handle)| (id, handle.join().unwrap()))
+                .collect()
+        })
+    }
 }
 fn setup_game(
-    mut commands: Commands,
-    materials: Res<Materials>,
+    mut sim: ResMut<Simulation>,
     mut controller: ResMut<AiManager>,
-    mut registry: ResMut<PlayerInfoRegistry>,
+    mut materials: ResMut<Materials>,
+    mut color_materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    spawn_snake_with_nodes(
-        &mut commands,
-        PlayerId(0),
-        Position::random(ARENA_WIDTH, ARENA_HEIGHT),
-        Velocity::random(CONST_SPEED),
-        3,
-        &materials,
-    );
-    registry.player_infos.insert(
+    info!("Match seed: {}", sim.seed);
+    sim.add_player(
         PlayerId(0),
         PlayerInfo {
             username: "player".to_string(),
             is_ai: false,
+            color: None,
         },
     );
     match controller.load_all_ai("bin/activated") {
@@ -143,288 +275,249 @@ fn setup_game(
             error!("Could not load ai: {:?}", err);
         }
     }
-    match controller.initialize_all_ai(&mut commands, &materials, &mut registry) {
-        Ok(()) => {}
-        Err(err) => {
-            error!("Could not initialize ai: {:?}", err);
-        }
-    }
-}
-type CollectSnakeQuery<'a, 'b> = Query<
-    'a,
-    (
-        &'b Transform,
-        &'b PlayerId,
-        Entity,
-        Option<&'b Radius>,
-        Option<&'b SnakeHead>,
-        Option<&'b SnakeSegment>,
-    ),
->;
-fn collect_snakes<'a>(
-    snake_components: &'a CollectSnakeQuery,
-    registry: &PlayerInfoRegistry,
-) -> BTreeMap<PlayerId, SnakeBody<&'a Transform>> {
-    let mut world = SnakeWorld::default();
-    for (trans, player, entity, radius, head, segment) in snake_components.iter() {
-        let snake = world.snakes.entry(*player).or_default();
-        snake.player_id = *player;
-        if head.is_some() {
-            snake.body.insert(
-                0,
-                SnakeNode {
-                    seg_id: 0,
-                    trans,
-                    entity: Some(entity),
-                },
-            );
-            snake.head_radius = radius.map(|x| *x);
-            snake.player_info = registry.player_infos.get(player).map(|x| x.clone());
-        } else if let Some(seg) = segment {
-            snake.body.insert(
-                seg.0,
-                SnakeNode {
-                    seg_id: seg.0,
-                    trans,
-                    entity: Some(entity),
-                },
-            );
-        } else {
-            unreachable!()
-        }
-    }
-    world.snakes
-}
-fn snake_move(
-    mut snake_components: Query<(
-        &mut Transform,
-        &PlayerId,
-        Option<&Velocity>,
-        Option<&SnakeHead>,
-        Option<&SnakeSegment>,
-    )>,
-    time: Res<Time>,
-    mut timer: Local<SnakeMoveTimer>,
-) {
-    if timer.0.tick(time.delta()).finished() {
-        let mut snakes: HashMap<PlayerId, SnakeBody<Mut<Transform>>> = Default::default();
-        for (trans, player, vel, head, segment) in snake_components.iter_mut() {
-            let snake = snakes.entry(*player).or_default();
-            if head.is_some() {
-                snake.body.insert(
-                    0,
-                    SnakeNode {
-                        seg_id: 0,
-                        trans,
-                        entity: None,
-                    },
-                );
-                snake.head_speed = Some(vel.cloned().unwrap());
-            } else if let Some(seg) = segment {
-                snake.body.insert(
-                    seg.0,
-                    SnakeNode {
-                        seg_id: seg.0,
-                        trans,
-                        entity: None,
-                    },
-                );
-            } else {
-                unreachable!()
-            }
-        }
-
-        for snake in snakes.values_mut() {
-            let head_vel = snake.head_speed.unwrap();
-            let mut body: Vec<_> = snake.body.values_mut().collect();
-            for i in (1..body.len()).rev() {
-                let mut trans =
-                    body[i].trans.translation * 0.95 + body[i - 1].trans.translation * 0.05;
-                trans.z = 0.0;
-                let mut from = body[i].trans.translation;
-                from.z = 0.0;
-                let diff = trans - from;
-                body[i].trans.translation = trans;
-
-                body[i].trans.rotation = Quat::from_rotation_arc(Vec3::X, diff.normalize());
-            }
-            body[0].trans.translation +=
-                CONST_SPEED * TICK * Vec3::new(head_vel.0.x.clone(), head_vel.0.y.clone(), 0.0);
+    controller.initialize_all_ai(&mut sim);
+    for snake in sim.snakes.values() {
+        if let Some([r, g, b]) = snake.player_info.as_ref().and_then(|x| x.color) {
+            let color = Color::rgb_u8(r, g, b);
+            let id = snake.player_id.0 as usize;
+            materials.colors[id] = color;
+            materials.head_material[id] = color_materials.add(color.into());
         }
     }
+    sim.start();
 }
-
-fn food_spawner(
-    mut commands: Commands,
-    materials: Res<Materials>,
-    time: Res<Time>,
-    mut timer: Local<FoodSpawnTimer>,
+/// Arrows turn the snake of the player. Without them, holding the left mouse button steers it
+/// towards the cursor. Space, the up arrow or the right mouse button boost.
+fn process_keyboard_input(
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    mut event: EventWriter<MovementEvent>,
 ) {
-    if timer.0.tick(time.delta()).finished() {
-        spawn_food(
-            &mut commands,
-            Position::random(ARENA_WIDTH, ARENA_HEIGHT),
-            &materials,
-        );
-    }
-}
-
-fn rotate((x, y): (f32, f32), theta: f32) -> Vec2 {
-    let (x2, y2) = (theta.cos(), theta.sin());
-    Vec2::new(
-        x.clone() * x2.clone() - y.clone() * y2.clone(),
-        x * y2 + y * x2,
-    )
-}
-fn process_keyboard_input(keys: Res<Input<KeyCode>>, mut event: EventWriter<MovementEvent>) {
-    if keys.pressed(KeyCode::Left) && keys.pressed(KeyCode::Right) {
-        return;
-    }
-    if keys.pressed(KeyCode::Left) {
-        event.send(MovementEvent {
-            player_id: PlayerId(0),
-            command: MovementCommand::TurnLeft,
-        });
+    let boost = keys.pressed(KeyCode::Space)
+        || keys.pressed(KeyCode::Up)
+        || mouse.pressed(MouseButton::Right);
+    let movement = if keys.pressed(KeyCode::Left) && keys.pressed(KeyCode::Right) {
+        MovementCommand::NoOps
+    } else if keys.pressed(KeyCode::Left) {
+        MovementCommand::TurnLeft
     } else if keys.pressed(KeyCode::Right) {
-        event.send(MovementEvent {
-            player_id: PlayerId(0),
-            command: MovementCommand::TurnRight,
-        });
-    }
+        MovementCommand::TurnRight
+    } else {
+        windows
+            .get_primary()
+            .filter(|_| mouse.pressed(MouseButton::Left))
+            .and_then(|window| {
+                // the camera is centered on the arena and draws one unit per pixel
+                let size = Vec2::new(window.width(), window.height());
+                window
+                    .cursor_position()
+                    .map(|cursor| MovementCommand::FaceTowards(Position(cursor - size / 2.0)))
+            })
+            .unwrap_or(MovementCommand::NoOps)
+    };
+    event.send(MovementEvent {
+        player_id: PlayerId(0),
+        action: PlayerAction { movement, boost },
+    });
 }
 struct MovementEvent {
     player_id: PlayerId,
-    command: MovementCommand,
-}
-fn process_movement(
-    mut events: EventReader<MovementEvent>,
-    mut q: Query<(&mut Velocity, &mut Transform, &PlayerId), With<SnakeHead>>,
-) {
-    const OMEGA: f32 = 2.0 * std::f32::consts::PI;
-    const THETA: f32 = OMEGA * TICK;
-    let mut movements = HashMap::default();
-    for event in events.iter() {
-        let command: &MovementCommand = &event.command;
-        let angle = match command {
-            MovementCommand::TurnLeft => THETA,
-            MovementCommand::TurnRight => -THETA,
-            MovementCommand::NoOps => 0.0,
-        };
-        movements.insert(event.player_id, angle);
-    }
-    for q in q.iter_mut() {
-        let (mut vel, mut trans, player_id): (Mut<Velocity>, Mut<Transform>, &PlayerId) = q;
-        if let Some(angle) = movements.get(&player_id) {
-            *vel = Velocity(rotate((vel.0.x.clone(), vel.0.y.clone()), angle.clone()));
-            trans.rotate(Quat::from_rotation_z(angle.clone()));
-        }
-    }
+    action: PlayerAction,
 }
 fn drive_all_ai(
     mut ai_manager: ResMut<AiManager>,
-    snake_components: CollectSnakeQuery,
-    foods: Query<&Transform, With<Food>>,
+    mut sim: ResMut<Simulation>,
     mut events: EventWriter<MovementEvent>,
-    registry: Res<PlayerInfoRegistry>,
 ) {
-    let mut world = SnakeWorld::default();
-    for trans in foods.iter() {
-        world.foods.push(FoodBody {
-            pos: Position(trans.translation.xy()),
-        })
+    if sim.phase != MatchPhase::Running {
+        return;
     }
-    world.snakes = collect_snakes(&snake_components, &registry);
-
-    for (id, ai) in ai_manager.ais.iter_mut() {
-        ai.feed_input(&world).unwrap();
-        let output = ai.get_output().unwrap();
+    ai_manager.restart_failed_ai(&mut sim);
+    let world = sim.world();
+    for (id, output) in ai_manager.query_all_ai(&world) {
+        let output = match output {
+            Ok(output) => output,
+            Err(err) if err.is::<ProtocolError>() => {
+                let name = ai_manager.ais[&id].controller.name();
+                warn!("AI {} of player {}: {}", name, id.0, err);
+                MovementCommand::NoOps.into()
+            }
+            Err(err) => {
+                ai_manager.fail(id, err, &mut sim);
+                continue;
+            }
+        };
         events.send(MovementEvent {
-            player_id: *id,
-            command: output,
+            player_id: id,
+            action: output,
         })
     }
 }
-fn locate_food(
-    pos: Vec3,
-    radius: Radius,
-    foods: &Query<(&Transform, &Radius, Entity), With<Food>>,
-) -> Option<(Entity, Vec3)> {
-    for p in foods.iter() {
-        let (trans, radius1, entity): (&Transform, &Radius, Entity) = p;
-        if trans.translation.distance(pos).abs() < radius.0.clone() + radius1.0.clone() {
-            return Some((entity, trans.translation));
-        }
-    }
-    None
+fn step_simulation(mut events: EventReader<MovementEvent>, mut sim: ResMut<Simulation>) {
+    let actions: HashMap<PlayerId, PlayerAction> = events
+        .iter()
+        .map(|event| (event.player_id, event.action))
+        .collect();
+    sim.step(&actions);
 }
-fn eat_food_and_extend(
-    mut commands: Commands,
-    snake_components: CollectSnakeQuery,
-    foods: Query<(&Transform, &Radius, Entity), With<Food>>,
-    materials: Res<Materials>,
-    registry: Res<PlayerInfoRegistry>,
+/// Hands the results to the AIs once per finished round
+fn announce_results(
+    mut sim: ResMut<Simulation>,
+    mut ai_manager: ResMut<AiManager>,
+    mut announced_round: Local<u32>,
 ) {
-    let snakes = collect_snakes(&snake_components, &registry);
-    for (player, snake) in snakes {
-        let first = snake.body.values().next().unwrap();
-        if let Some((food, food_pos)) =
-            locate_food(first.trans.translation, snake.head_radius.unwrap(), &foods)
-        {
-            commands.entity(food).despawn();
-            spawn_snake_segment(
-                &mut commands,
-                snake.body.len() as _,
-                player,
-                Position(food_pos.xy()),
-                &materials,
-            );
+    let results = match &sim.results {
+        Some(results) if results.round != *announced_round => results.clone(),
+        _ => return,
+    };
+    info!("Round {} is over: {:?}", results.round, results.standings);
+    let mut failed = vec![];
+    for (id, slot) in ai_manager.ais.iter_mut().filter(|(_, slot)| slot.playing) {
+        if let Err(err) = slot.controller.game_over(&results) {
+            failed.push((*id, err));
         }
     }
+    for (id, err) in failed {
+        ai_manager.fail(id, err, &mut sim);
+    }
+    *announced_round = results.round;
+}
+fn start_next_round(keys: Res<Input<KeyCode>>, mut sim: ResMut<Simulation>) {
+    if keys.just_pressed(KeyCode::Return) {
+        sim.next_round();
+    }
 }
-fn death_detection(
+fn heading(dir: Vec2) -> Option<Quat> {
+    if dir.length_squared() > 0.0 {
+        let dir = dir.normalize();
+        Some(Quat::from_rotation_arc(
+            Vec3::X,
+            Vec3::new(dir.x, dir.y, 0.0),
+        ))
+    } else {
+        None
+    }
+}
+/// Mirrors the snakes of the simulation into sprites
+fn render_snakes(
     mut commands: Commands,
-    snake_components: CollectSnakeQuery,
+    sim: Res<Simulation>,
     materials: Res<Materials>,
-    registry: Res<PlayerInfoRegistry>,
+    mut sprites: Query<
+        (
+            Entity,
+            &PlayerId,
+            &mut Transform,
+            Option<&SnakeHead>,
+            Option<&SnakeSegment>,
+        ),
+        With<SnakeComponent>,
+    >,
 ) {
-    let snakes = collect_snakes(&snake_components, &registry);
-    for (player, snake) in &snakes {
-        for (player2, snake2) in &snakes {
-            if player == player2 {
+    let mut rendered = HashSet::default();
+    for (entity, player, mut trans, head, segment) in sprites.iter_mut() {
+        let seg_id = match (head, segment) {
+            (Some(_), _) => 0,
+            (None, Some(seg)) => seg.0 as usize,
+            (None, None) => unreachable!(),
+        };
+        let snake = match sim.snakes.get(player) {
+            Some(snake) if snake.alive && seg_id < snake.body.len() => snake,
+            _ => {
+                commands.entity(entity).despawn();
                 continue;
             }
-            let mut collision = false;
-            for node in snake2.body.values() {
-                if snake
-                    .body
-                    .values()
-                    .next()
-                    .unwrap()
-                    .trans
-                    .translation
-                    .distance(node.trans.translation)
-                    < snake.head_radius.unwrap().0 + snake2.head_radius.unwrap().0
-                {
-                    collision = true;
-                }
+        };
+        let pos = snake.body[seg_id].0;
+        trans.translation.x = pos.x;
+        trans.translation.y = pos.y;
+        let dir = if seg_id == 0 {
+            snake.velocity.0
+        } else {
+            snake.body[seg_id - 1].0 - pos
+        };
+        if let Some(rotation) = heading(dir) {
+            trans.rotation = rotation;
+        }
+        rendered.insert((*player, seg_id));
+    }
+    for snake in sim.snakes.values().filter(|snake| snake.alive) {
+        for (seg_id, pos) in snake.body.iter().enumerate() {
+            if rendered.contains(&(snake.player_id, seg_id)) {
+                continue;
             }
-            if collision {
-                for n in snake.body.values() {
-                    commands.entity(n.entity.unwrap()).despawn();
-                }
-                spawn_snake_with_nodes(
+            if seg_id == 0 {
+                spawn_snake_head(
                     &mut commands,
-                    *player,
-                    Position::random(ARENA_WIDTH, ARENA_HEIGHT),
-                    Velocity::random(CONST_SPEED),
-                    3,
+                    snake.player_id,
+                    *pos,
+                    snake.velocity,
+                    &materials,
+                );
+            } else {
+                spawn_snake_segment(
+                    &mut commands,
+                    seg_id as _,
+                    snake.player_id,
+                    *pos,
                     &materials,
                 );
             }
         }
     }
 }
+/// Mirrors the foods of the simulation into sprites
+fn render_foods(
+    mut commands: Commands,
+    sim: Res<Simulation>,
+    materials: Res<Materials>,
+    sprites: Query<(Entity, &FoodId), With<Food>>,
+) {
+    let mut rendered = HashSet::default();
+    for (entity, id) in sprites.iter() {
+        if sim.foods.iter().any(|food| food.id == *id) {
+            rendered.insert(*id);
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+    for food in &sim.foods {
+        if !rendered.contains(&food.id) {
+            spawn_food(
+                &mut commands,
+                food.id,
+                food.kind,
+                food.pos,
+                food.radius,
+                &materials,
+            );
+        }
+    }
+}
 struct LeaderBoard;
+struct MatchStatus;
+struct AiLogText;
+
+/// AI whose stderr is shown in the corner, cycled with Tab
+#[derive(Default)]
+struct SelectedPlayer(Option<PlayerId>);
+
+fn select_log_player(
+    keys: Res<Input<KeyCode>>,
+    ai_manager: Res<AiManager>,
+    mut selected: ResMut<SelectedPlayer>,
+) {
+    if !keys.just_pressed(KeyCode::Tab) {
+        return;
+    }
+    let mut ids = ai_manager.ais.keys().copied();
+    selected.0 = match selected.0 {
+        // the one after the current one; after the last one the log is hidden
+        Some(current) => ids.find(|&id| id > current),
+        None => ids.next(),
+    };
+}
 
 fn draw_text<'a, 'b>(
     commands: &'b mut Commands<'a>,
@@ -455,29 +548,33 @@ fn draw_leaderboard(
     mut commands: Commands,
     last: Query<Entity, With<LeaderBoard>>,
     asset_server: Res<AssetServer>,
-    snakes: CollectSnakeQuery,
+    sim: Res<Simulation>,
     materials: Res<Materials>,
-    registry: Res<PlayerInfoRegistry>,
 ) {
     last.for_each(|x| commands.entity(x).despawn());
     let font: Handle<Font> = asset_server.load("fonts/Arial.ttf");
-    let snakes = collect_snakes(&snakes, &registry);
     let pos_x = 300.0;
     let mut pos_y = 0.0;
-    for snake in snakes.values() {
-        let color = materials.colors[snake.player_id.0.clone() as usize];
-        // | player_name | 0 score(s) |
+    for (player_id, points) in sim.leaderboard() {
+        let snake = &sim.snakes[&player_id];
+        let score = sim.scores.get(&player_id).cloned().unwrap_or_default();
+        let color = materials.colors[player_id.0.clone() as usize];
+        // | player_name | 0 point(s) | kills/deaths | length |
         draw_text(
             &mut commands,
             format!(
-                "{}.{}: {} score(s)",
-                snake.player_id.0,
+                "{}.{}: {} point(s), {}/{} k/d, length {}{}",
+                player_id.0,
                 snake
                     .player_info
                     .as_ref()
                     .map(|x| x.username.as_str())
                     .unwrap_or("unnamed"),
-                snake.body.len()
+                points.round(),
+                score.kills,
+                score.deaths,
+                snake.length(),
+                if snake.alive { "" } else { " (out)" }
             ),
             24.0,
             color,
@@ -488,8 +585,105 @@ fn draw_leaderboard(
         pos_y -= 20.0;
     }
 }
+fn draw_match_status(
+    mut commands: Commands,
+    last: Query<Entity, With<MatchStatus>>,
+    asset_server: Res<AssetServer>,
+    sim: Res<Simulation>,
+    materials: Res<Materials>,
+) {
+    last.for_each(|x| commands.entity(x).despawn());
+    let font: Handle<Font> = asset_server.load("fonts/Arial.ttf");
+    let mut lines = vec![];
+    match sim.phase {
+        MatchPhase::Lobby => lines.push(("Waiting for players".to_string(), Color::WHITE)),
+        MatchPhase::Countdown { remaining } => lines.push((
+            format!(
+                "Round {} starts in {}",
+                sim.round,
+                (remaining as f32 * TICK).ceil()
+            ),
+            Color::WHITE,
+        )),
+        MatchPhase::Running => {}
+        MatchPhase::Finished => {
+            let results = sim.results.as_ref().unwrap();
+            let title = match results.winner() {
+                Some(winner) => format!("Round {}: {} wins!", results.round, winner.username),
+                None => format!("Round {}: draw", results.round),
+            };
+            lines.push((title, Color::WHITE));
+            for standing in &results.standings {
+                lines.push((
+                    format!(
+                        "#{} {}: {} point(s)",
+                        standing.rank,
+                        standing.username,
+                        standing.score.round()
+                    ),
+                    materials.colors[standing.player_id.0 as usize],
+                ));
+            }
+            lines.push(("Press Enter for the next round".to_string(), Color::WHITE));
+        }
+    }
+    let mut pos_y = 20.0 * lines.len() as f32 / 2.0;
+    for (text, color) in lines {
+        draw_text(
+            &mut commands,
+            text,
+            24.0,
+            color,
+            Vec2::new(-150.0, pos_y),
+            font.clone(),
+        )
+        .insert(MatchStatus);
+        pos_y -= 24.0;
+    }
+}
+fn draw_ai_log(
+    mut commands: Commands,
+    last: Query<Entity, With<AiLogText>>,
+    asset_server: Res<AssetServer>,
+    ai_manager: Res<AiManager>,
+    selected: Res<SelectedPlayer>,
+    materials: Res<Materials>,
+) {
+    last.for_each(|x| commands.entity(x).despawn());
+    let id = match selected.0 {
+        Some(id) => id,
+        None => return,
+    };
+    let slot = match ai_manager.ais.get(&id) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let font: Handle<Font> = asset_server.load("fonts/Arial.ttf");
+    let color = materials.colors[id.0 as usize];
+    let mut pos_y = -160.0;
+    let title = format!("stderr of {}:", slot.controller.name());
+    for line in std::iter::once(title).chain(slot.controller.recent_log()) {
+        draw_text(
+            &mut commands,
+            line,
+            14.0,
+            color,
+            Vec2::new(-300.0, pos_y),
+            font.clone(),
+        )
+        .insert(AiLogText);
+        pos_y -= 14.0;
+    }
+}
 // Entity Component System
 fn main() {
+    let config = match MatchConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{:?}", err);
+            std::process::exit(2);
+        }
+    };
     App::build()
         .insert_resource(ClearColor(Color::rgb(0.04, 0.04, 0.04)))
         .insert_resource(WindowDescriptor {
@@ -499,19 +693,35 @@ fn main() {
             ..Default::default()
         })
         .insert_resource(AiManager::default())
-        .insert_resource(PlayerInfoRegistry::default())
+        .insert_resource(SelectedPlayer::default())
+        .insert_resource(Simulation::new(config))
         .add_event::<MovementEvent>()
         .add_startup_system(setup.system())
-        .add_startup_stage("setup_game", SystemStage::single(setup_game.system()))
+        .add_startup_stage(
+            "setup_game",
+            SystemStage::parallel()
+                .with_system(setup_game.system())
+                .with_system(draw_arena_border.system())
+                .with_system(draw_obstacles.system()),
+        )
         .add_system(exit_on_esc_system.system())
-        .add_system(food_spawner.system())
-        .add_system(process_keyboard_input.system())
-        .add_system(eat_food_and_extend.system())
-        .add_system(death_detection.system())
-        .add_system(drive_all_ai.system())
-        .add_system(process_movement.system())
-        .add_system(snake_move.system())
+        .add_stage_before(
+            CoreStage::Update,
+            FIXED_UPDATE,
+            SystemStage::parallel()
+                .with_run_criteria(FixedTimestep::step(TICK as f64))
+                .with_system(process_keyboard_input.system().label("input"))
+                .with_system(drive_all_ai.system().label("input"))
+                .with_system(step_simulation.system().label("step").after("input"))
+                .with_system(announce_results.system().after("step")),
+        )
+        .add_system(start_next_round.system())
+        .add_system(render_snakes.system())
+        .add_system(render_foods.system())
         .add_system(draw_leaderboard.system())
+        .add_system(draw_match_status.system())
+        .add_system(select_log_player.system())
+        .add_system(draw_ai_log.system())
         .add_plugin(LogDiagnosticsPlugin::default())
         // .add_plugin(FrameTimeDiagnosticsPlugin::default())
         .add_plugins(DefaultPlugins)
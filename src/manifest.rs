@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Looked up, in this order, inside a directory of `bin/activated`
+pub const MANIFEST_FILES: [&str; 2] = ["ai.toml", "ai.json"];
+
+/// How to launch an AI, loaded from a TOML or JSON file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AiManifest {
+    /// Program to run. A relative path with a `/` in it is relative to the manifest,
+    /// anything else is looked up in `PATH`.
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Relative to the manifest. Defaults to the directory of the manifest.
+    pub working_dir: Option<PathBuf>,
+    /// Added to the environment of the game
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Shown instead of the username the AI sends
+    pub name: Option<String>,
+    /// Colour of the snake head as `[r, g, b]`, each from 0 to 255
+    pub color: Option<[u8; 3]>,
+    /// Protocol version spoken from the very first message, INIT included.
+    /// Without it the AI gets the text INIT and picks a version in its reply.
+    pub protocol: Option<u32>,
+    /// File the AI was found at
+    #[serde(skip)]
+    pub source: PathBuf,
+}
+
+impl AiManifest {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read manifest {}", path.display()))?;
+        let mut manifest: Self = match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => serde_json::from_str(&text)
+                .with_context(|| format!("Could not parse manifest {}", path.display()))?,
+            _ => toml::from_str(&text)
+                .with_context(|| format!("Could not parse manifest {}", path.display()))?,
+        };
+        // absolute, as the command is started from inside `working_dir`
+        let base_dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let base_dir = base_dir
+            .canonicalize()
+            .with_context(|| format!("Could not resolve {}", base_dir.display()))?;
+        if manifest.command.contains('/') && Path::new(&manifest.command).is_relative() {
+            let command = base_dir.join(&manifest.command);
+            manifest.command = command.to_str().unwrap().to_owned();
+        }
+        manifest.working_dir = Some(match manifest.working_dir.take() {
+            Some(dir) => base_dir.join(dir),
+            None => base_dir.to_owned(),
+        });
+        manifest.source = path.to_owned();
+        Ok(manifest)
+    }
+    /// Runs a bare file: `.py` with `python3`, anything else as it is
+    pub fn for_executable(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let file = path.to_str().unwrap().to_owned();
+        let (command, args) = if file.ends_with(".py") {
+            ("python3".to_owned(), vec![file])
+        } else {
+            (file, vec![])
+        };
+        Self {
+            command,
+            args,
+            working_dir: None,
+            env: Default::default(),
+            name: None,
+            color: None,
+            protocol: None,
+            source: path.to_owned(),
+        }
+    }
+    /// Works out how to launch the AI at `path`, an entry of `bin/activated`.
+    /// The error tells why the entry is not an AI.
+    pub fn discover(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+        if file_name.starts_with('.') {
+            anyhow::bail!("hidden file");
+        }
+        if path.is_dir() {
+            return match MANIFEST_FILES
+                .iter()
+                .map(|name| path.join(name))
+                .find(|manifest| manifest.is_file())
+            {
+                Some(manifest) => Self::load(manifest),
+                None => anyhow::bail!("directory without {}", MANIFEST_FILES.join(" or ")),
+            };
+        }
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") | Some("json") => Self::load(path),
+            Some("py") => Ok(Self::for_executable(path)),
+            _ if is_executable(path)? => Ok(Self::for_executable(path)),
+            _ => anyhow::bail!("neither a manifest nor an executable"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = std::fs::metadata(path)?;
+    Ok(metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> Result<bool> {
+    Ok(path.extension().map_or(false, |x| x == "exe"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_become_absolute() {
+        let dir = std::env::temp_dir().join(format!("the_snakes-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("bot")).unwrap();
+        let path = dir.join("ai.toml");
+        std::fs::write(&path, "name = \"bot\"\ncommand = \"./bot/run\"\n").unwrap();
+        let manifest = AiManifest::load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let manifest = manifest.unwrap();
+        let working_dir = manifest.working_dir.unwrap();
+        assert!(working_dir.is_absolute());
+        assert!(Path::new(&manifest.command).is_absolute());
+        assert!(Path::new(&manifest.command).starts_with(&working_dir));
+    }
+}
@@ -0,0 +1,1020 @@
+use crate::arena::Obstacle;
+use crate::config::{EdgeMode, MatchConfig};
+use crate::controller::{MovementCommand, PlayerAction, ProtocolError};
+use crate::simulation::{
+    delta, wrap, DeathCause, EndReason, MatchResults, BOOST_BURN_TICKS, BOOST_FACTOR, FOLLOW_RATE,
+    FOOD_RADIUS, HEAD_SPEED, OMEGA, SNAKE_RADIUS,
+};
+use crate::{
+    FoodId, FoodKind, PlayerId, Position, SnakeBody, SnakeWorld, ARENA_HEIGHT, ARENA_WIDTH,
+    CONST_SPEED, TICK,
+};
+use anyhow::Context;
+use bevy::math::Vec2;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+
+/// Versions of the stdio protocol the game speaks, advertised in the INIT block
+pub const SUPPORTED_VERSIONS: &[u32] = &[1, 2, 3, 4, 5];
+/// Spoken with AIs that don't declare a version
+pub const DEFAULT_VERSION: u32 = 1;
+
+/// Reply of an AI to INIT
+#[derive(Debug)]
+pub struct Hello {
+    pub username: String,
+    /// Version the AI wants to speak from now on, if it said
+    pub protocol: Option<u32>,
+}
+
+/// Writes what the game sends to an AI, and parses its replies, in one version of the protocol.
+/// Every AI starts with the text INIT of version 1, unless its manifest picks another version.
+pub trait Protocol: Send + Sync {
+    fn version(&self) -> u32;
+    fn write_init(
+        &mut self,
+        out: &mut dyn Write,
+        player_id: PlayerId,
+        config: &MatchConfig,
+    ) -> Result<()> {
+        write_text_init(out, player_id, config)
+    }
+    fn parse_hello(&mut self, line: &str) -> anyhow::Result<Hello> {
+        parse_text_hello(line)
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()>;
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()>;
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()>;
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction>;
+}
+
+/// `None` if the game does not speak `version`
+pub fn protocol(version: u32, config: &MatchConfig) -> Option<Box<dyn Protocol>> {
+    match version {
+        1 => Some(Box::new(TextV1)),
+        2 => Some(Box::new(TextV2)),
+        3 => Some(Box::new(JsonLines)),
+        4 => Some(Box::new(Binary)),
+        5 => Some(Box::new(TextDelta::new(config))),
+        _ => None,
+    }
+}
+
+fn write_text_init(out: &mut dyn Write, player_id: PlayerId, config: &MatchConfig) -> Result<()> {
+    writeln!(out, "INIT BEGIN")?;
+    write!(out, "protocol_versions")?;
+    for version in SUPPORTED_VERSIONS {
+        write!(out, " {}", version)?;
+    }
+    writeln!(out)?;
+    writeln!(out, "player_id {}", player_id.0)?;
+    writeln!(out, "edge_mode {}", config.edge_mode)?;
+    writeln!(out, "arena_width {}", ARENA_WIDTH)?;
+    writeln!(out, "arena_height {}", ARENA_HEIGHT)?;
+    writeln!(out, "const_speed {}", CONST_SPEED)?;
+    writeln!(out, "head_speed {}", HEAD_SPEED)?;
+    writeln!(out, "turn_rate {}", OMEGA)?;
+    writeln!(out, "tick_seconds {}", TICK)?;
+    // older bots route lines by a `snake` or `food` prefix, so no key may start with one
+    writeln!(out, "radius_snake {}", SNAKE_RADIUS)?;
+    writeln!(out, "radius_food {}", FOOD_RADIUS)?;
+    writeln!(out, "boost_factor {}", BOOST_FACTOR)?;
+    writeln!(out, "boost_burn_ticks {}", BOOST_BURN_TICKS)?;
+    writeln!(out, "response_deadline_ms {}", config.response_deadline_ms)?;
+    for obstacle in &config.arena.obstacles {
+        match obstacle {
+            Obstacle::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => writeln!(out, "obstacle rect {} {} {} {}", x, y, width, height)?,
+            Obstacle::Circle { x, y, radius } => {
+                writeln!(out, "obstacle circle {} {} {}", x, y, radius)?
+            }
+            Obstacle::Polygon { points } => {
+                write!(out, "obstacle polygon")?;
+                for [x, y] in points {
+                    write!(out, " {}", Position(Vec2::new(*x, *y)))?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+    writeln!(out, "INIT END")
+}
+
+/// A NaN or infinite number in an action would make the position of the snake NaN for good
+fn finite(name: &str, value: f32) -> anyhow::Result<f32> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(ProtocolError(format!("Invalid {} {}", name, value)).into())
+    }
+}
+
+/// `username <name> [protocol <version>]`
+fn parse_text_hello(line: &str) -> anyhow::Result<Hello> {
+    let mut spt = line.split(" ");
+    if spt.next() != Some("username") {
+        anyhow::bail!("You must begin with username");
+    }
+    let username = spt.next().context("Could not find username")?.trim();
+    if username.is_empty() {
+        anyhow::bail!("Could not leave username empty");
+    }
+    let mut protocol = None;
+    if spt.next().map(|x| x.trim()) == Some("protocol") {
+        let v = spt
+            .next()
+            .context("Could not find protocol version")?
+            .trim();
+        protocol = Some(
+            v.parse()
+                .with_context(|| format!("Invalid protocol version {:?}", v))?,
+        );
+    }
+    Ok(Hello {
+        username: username.to_owned(),
+        protocol,
+    })
+}
+
+/// Names from manifests may have spaces, which would split them into several words
+fn token(name: &str) -> String {
+    name.replace(char::is_whitespace, "_")
+}
+
+/// The original line based text protocol
+pub struct TextV1;
+impl Protocol for TextV1 {
+    fn version(&self) -> u32 {
+        1
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        writeln!(out, "MAP BEGIN")?;
+        writeln!(out, "tick {}", world.tick)?;
+        for snake in world.snakes.values().filter(|snake| snake.alive) {
+            write!(out, "snake {}", snake.player_id.0)?;
+            for node in snake.body.values() {
+                write!(out, " {}", node.trans)?;
+            }
+            writeln!(out)?;
+        }
+        for food in &world.foods {
+            writeln!(out, "food {}", food.pos)?;
+        }
+        writeln!(out, "MAP END")
+    }
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()> {
+        writeln!(out, "REQUEST_ACTION")
+    }
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()> {
+        writeln!(out, "GAME OVER BEGIN")?;
+        writeln!(out, "round {}", results.round)?;
+        writeln!(out, "tick {}", results.tick)?;
+        writeln!(out, "reason {}", results.reason)?;
+        match results.winner() {
+            Some(winner) => writeln!(out, "winner {}", winner.player_id.0)?,
+            None => writeln!(out, "winner none")?,
+        }
+        for standing in &results.standings {
+            writeln!(
+                out,
+                "result {} {} {} {} {}",
+                standing.rank,
+                standing.player_id.0,
+                token(&standing.username),
+                standing.score,
+                standing.alive as i32
+            )?;
+        }
+        writeln!(out, "GAME OVER END")
+    }
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        let mut spt = line.split_whitespace();
+        let cmd = spt.next();
+        let mut number = |name: &str| -> anyhow::Result<f32> {
+            let word = spt
+                .next()
+                .ok_or_else(|| ProtocolError(format!("Missing {}", name)))?;
+            let value = word
+                .parse()
+                .map_err(|_| ProtocolError(format!("Invalid {} {:?}", name, word)))?;
+            finite(name, value)
+        };
+        let movement = match cmd {
+            Some("turn_left") => MovementCommand::TurnLeft,
+            Some("turn_right") => MovementCommand::TurnRight,
+            Some("straight") => MovementCommand::NoOps,
+            Some("steer") => MovementCommand::Steer(number("angle")?),
+            Some("face_towards") => {
+                let x = number("x")?;
+                let y = number("y")?;
+                MovementCommand::FaceTowards(Position(Vec2::new(x, y)))
+            }
+            // boosting straight ahead
+            Some("boost") => {
+                return Ok(PlayerAction {
+                    movement: MovementCommand::NoOps,
+                    boost: true,
+                })
+            }
+            Some(x) => {
+                return Err(ProtocolError(format!("Does not recognize command {:?}", x)).into())
+            }
+            None => return Err(ProtocolError("You must not leave an empty line".to_owned()).into()),
+        };
+        Ok(PlayerAction {
+            movement,
+            boost: spt.any(|x| x == "boost"),
+        })
+    }
+}
+
+fn write_snake_v2(out: &mut dyn Write, snake: &SnakeBody<Position>) -> Result<()> {
+    let velocity = snake.head_speed.map_or(Vec2::ZERO, |x| x.0);
+    write!(
+        out,
+        "snake {} velocity ({},{}) radius {} length {} score {} alive {} username {} body",
+        snake.player_id.0,
+        velocity.x,
+        velocity.y,
+        snake.head_radius.map_or(0.0, |x| x.0),
+        snake.body.len(),
+        snake.score,
+        snake.alive as i32,
+        token(
+            snake
+                .player_info
+                .as_ref()
+                .map(|x| x.username.as_str())
+                .unwrap_or("unnamed")
+        ),
+    )?;
+    for node in snake.body.values() {
+        write!(out, " {}", node.trans)?;
+    }
+    writeln!(out)
+}
+
+/// `kill <victim> <killer|none> <cause>` for each death of the last tick
+fn write_kills(out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+    for kill in &world.kills {
+        match kill.killer {
+            Some(killer) => writeln!(out, "kill {} {} {}", kill.victim.0, killer.0, kill.cause)?,
+            None => writeln!(out, "kill {} none {}", kill.victim.0, kill.cause)?,
+        }
+    }
+    Ok(())
+}
+
+/// Version 1 with the state of each snake in its MAP line:
+/// `snake <id> velocity (x,y) radius <r> length <n> score <n> alive <0|1> username <name> body (x,y)...`
+pub struct TextV2;
+impl Protocol for TextV2 {
+    fn version(&self) -> u32 {
+        2
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        writeln!(out, "MAP BEGIN")?;
+        writeln!(out, "tick {}", world.tick)?;
+        for snake in world.snakes.values() {
+            write_snake_v2(out, snake)?;
+        }
+        for food in &world.foods {
+            writeln!(
+                out,
+                "food {} value {} kind {}",
+                food.pos, food.value, food.kind
+            )?;
+        }
+        write_kills(out, world)?;
+        writeln!(out, "MAP END")
+    }
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()> {
+        TextV1.write_request(out)
+    }
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()> {
+        TextV1.write_game_over(out, results)
+    }
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        TextV1.parse_action(line)
+    }
+}
+
+/// One JSON object per line, told apart by its `type`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonMessage<'a> {
+    Init {
+        protocol_versions: &'a [u32],
+        player_id: i32,
+        edge_mode: EdgeMode,
+        arena_width: f32,
+        arena_height: f32,
+        const_speed: f32,
+        head_speed: f32,
+        turn_rate: f32,
+        tick_seconds: f32,
+        radius_snake: f32,
+        radius_food: f32,
+        boost_factor: f32,
+        boost_burn_ticks: u32,
+        response_deadline_ms: u64,
+        obstacles: &'a [Obstacle],
+    },
+    Map {
+        tick: u64,
+        snakes: Vec<JsonSnake<'a>>,
+        foods: Vec<JsonFood>,
+        kills: Vec<JsonKill>,
+    },
+    RequestAction,
+    GameOver {
+        round: u32,
+        tick: u64,
+        reason: String,
+        winner: Option<i32>,
+        standings: Vec<JsonStanding<'a>>,
+    },
+}
+#[derive(Serialize)]
+struct JsonSnake<'a> {
+    player_id: i32,
+    username: &'a str,
+    velocity: [f32; 2],
+    radius: f32,
+    length: usize,
+    score: f32,
+    alive: bool,
+    body: Vec<[f32; 2]>,
+}
+#[derive(Serialize)]
+struct JsonFood {
+    pos: [f32; 2],
+    value: u32,
+    kind: FoodKind,
+}
+#[derive(Serialize)]
+struct JsonKill {
+    victim: i32,
+    killer: Option<i32>,
+    cause: DeathCause,
+}
+#[derive(Serialize)]
+struct JsonStanding<'a> {
+    rank: u32,
+    player_id: i32,
+    username: &'a str,
+    score: f32,
+    alive: bool,
+}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonHello {
+    username: String,
+    protocol: Option<u32>,
+}
+#[derive(Deserialize)]
+struct JsonReply {
+    #[serde(flatten)]
+    action: JsonAction,
+    #[serde(default)]
+    boost: bool,
+}
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum JsonAction {
+    TurnLeft,
+    TurnRight,
+    Straight,
+    Steer { angle: f32 },
+    FaceTowards { x: f32, y: f32 },
+}
+
+fn write_json(out: &mut dyn Write, message: &JsonMessage) -> Result<()> {
+    serde_json::to_writer(&mut *out, message)?;
+    writeln!(out)
+}
+
+/// Version 3: the messages of version 2 as JSON lines, described in the README
+pub struct JsonLines;
+impl Protocol for JsonLines {
+    fn version(&self) -> u32 {
+        3
+    }
+    fn write_init(
+        &mut self,
+        out: &mut dyn Write,
+        player_id: PlayerId,
+        config: &MatchConfig,
+    ) -> Result<()> {
+        let message = JsonMessage::Init {
+            protocol_versions: SUPPORTED_VERSIONS,
+            player_id: player_id.0,
+            edge_mode: config.edge_mode,
+            arena_width: ARENA_WIDTH,
+            arena_height: ARENA_HEIGHT,
+            const_speed: CONST_SPEED,
+            head_speed: HEAD_SPEED,
+            turn_rate: OMEGA,
+            tick_seconds: TICK,
+            radius_snake: SNAKE_RADIUS,
+            radius_food: FOOD_RADIUS,
+            boost_factor: BOOST_FACTOR,
+            boost_burn_ticks: BOOST_BURN_TICKS,
+            response_deadline_ms: config.response_deadline_ms,
+            obstacles: &config.arena.obstacles,
+        };
+        write_json(out, &message)
+    }
+    fn parse_hello(&mut self, line: &str) -> anyhow::Result<Hello> {
+        let hello: JsonHello = serde_json::from_str(line)
+            .map_err(|err| ProtocolError(format!("Invalid hello: {}", err)))?;
+        if hello.username.trim().is_empty() {
+            anyhow::bail!("Could not leave username empty");
+        }
+        Ok(Hello {
+            username: hello.username,
+            protocol: hello.protocol,
+        })
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        let snakes = world
+            .snakes
+            .values()
+            .map(|snake| {
+                let velocity = snake.head_speed.map_or(Vec2::ZERO, |x| x.0);
+                JsonSnake {
+                    player_id: snake.player_id.0,
+                    username: snake
+                        .player_info
+                        .as_ref()
+                        .map(|x| x.username.as_str())
+                        .unwrap_or("unnamed"),
+                    velocity: [velocity.x, velocity.y],
+                    radius: snake.head_radius.map_or(0.0, |x| x.0),
+                    length: snake.body.len(),
+                    score: snake.score,
+                    alive: snake.alive,
+                    body: snake
+                        .body
+                        .values()
+                        .map(|node| [node.trans.0.x, node.trans.0.y])
+                        .collect(),
+                }
+            })
+            .collect();
+        let foods = world
+            .foods
+            .iter()
+            .map(|food| JsonFood {
+                pos: [food.pos.0.x, food.pos.0.y],
+                value: food.value,
+                kind: food.kind,
+            })
+            .collect();
+        let kills = world
+            .kills
+            .iter()
+            .map(|kill| JsonKill {
+                victim: kill.victim.0,
+                killer: kill.killer.map(|x| x.0),
+                cause: kill.cause,
+            })
+            .collect();
+        let message = JsonMessage::Map {
+            tick: world.tick,
+            snakes,
+            foods,
+            kills,
+        };
+        write_json(out, &message)
+    }
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()> {
+        write_json(out, &JsonMessage::RequestAction)
+    }
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()> {
+        let message = JsonMessage::GameOver {
+            round: results.round,
+            tick: results.tick,
+            reason: results.reason.to_string(),
+            winner: results.winner().map(|x| x.player_id.0),
+            standings: results
+                .standings
+                .iter()
+                .map(|standing| JsonStanding {
+                    rank: standing.rank,
+                    player_id: standing.player_id.0,
+                    username: &standing.username,
+                    score: standing.score,
+                    alive: standing.alive,
+                })
+                .collect(),
+        };
+        write_json(out, &message)
+    }
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        let reply: JsonReply = serde_json::from_str(line)
+            .map_err(|err| ProtocolError(format!("Invalid action: {}", err)))?;
+        let movement = match reply.action {
+            JsonAction::TurnLeft => MovementCommand::TurnLeft,
+            JsonAction::TurnRight => MovementCommand::TurnRight,
+            JsonAction::Straight => MovementCommand::NoOps,
+            JsonAction::Steer { angle } => MovementCommand::Steer(finite("angle", angle)?),
+            JsonAction::FaceTowards { x, y } => {
+                MovementCommand::FaceTowards(Position(Vec2::new(finite("x", x)?, finite("y", y)?)))
+            }
+        };
+        Ok(PlayerAction {
+            movement,
+            boost: reply.boost,
+        })
+    }
+}
+
+/// Tags of the binary frames, see `c_ai/snakes_binary.h`
+pub const TAG_INIT: u32 = 1;
+pub const TAG_MAP: u32 = 2;
+pub const TAG_REQUEST_ACTION: u32 = 3;
+pub const TAG_GAME_OVER: u32 = 4;
+/// Usernames take this many bytes in binary frames, NUL padded and cut if longer
+pub const BINARY_NAME_LENGTH: usize = 32;
+
+/// Little endian payload of a binary frame
+#[derive(Default)]
+struct Payload(Vec<u8>);
+impl Payload {
+    fn u32(&mut self, x: u32) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+    fn i32(&mut self, x: i32) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, x: u64) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+    fn f32(&mut self, x: f32) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+    fn name(&mut self, name: &str) -> &mut Self {
+        let mut bytes = [0u8; BINARY_NAME_LENGTH];
+        // the last byte stays NUL
+        let len = name.len().min(BINARY_NAME_LENGTH - 1);
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.0.extend_from_slice(&bytes);
+        self
+    }
+    /// `u32 tag`, `u32 length` of the payload, then the payload
+    fn write_frame(&self, out: &mut dyn Write, tag: u32) -> Result<()> {
+        out.write_all(&tag.to_le_bytes())?;
+        out.write_all(&(self.0.len() as u32).to_le_bytes())?;
+        out.write_all(&self.0)
+    }
+}
+
+/// Version 4: what the game sends is in length prefixed binary frames, laid out so that C bots
+/// can `fread` them into the structs of `c_ai/snakes_binary.h`. The replies of the bot are the
+/// text lines of version 1.
+pub struct Binary;
+impl Protocol for Binary {
+    fn version(&self) -> u32 {
+        4
+    }
+    fn write_init(
+        &mut self,
+        out: &mut dyn Write,
+        player_id: PlayerId,
+        config: &MatchConfig,
+    ) -> Result<()> {
+        let edge_mode = match config.edge_mode {
+            EdgeMode::Wall => 0,
+            EdgeMode::Wrap => 1,
+            EdgeMode::Bounce => 2,
+        };
+        let mut payload = Payload::default();
+        payload
+            .u32(self.version())
+            .i32(player_id.0)
+            .u32(edge_mode)
+            .f32(ARENA_WIDTH)
+            .f32(ARENA_HEIGHT)
+            .f32(CONST_SPEED)
+            .f32(HEAD_SPEED)
+            .f32(OMEGA)
+            .f32(TICK)
+            .f32(SNAKE_RADIUS)
+            .f32(FOOD_RADIUS)
+            .u32(config.response_deadline_ms as u32)
+            .u32(config.arena.obstacles.len() as u32);
+        for obstacle in &config.arena.obstacles {
+            let (shape, values) = match obstacle {
+                Obstacle::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                } => (0, vec![*x, *y, *width, *height]),
+                Obstacle::Circle { x, y, radius } => (1, vec![*x, *y, *radius]),
+                Obstacle::Polygon { points } => (2, points.concat()),
+            };
+            payload.u32(shape).u32(values.len() as u32);
+            for value in values {
+                payload.f32(value);
+            }
+        }
+        payload.write_frame(out, TAG_INIT)
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        let mut payload = Payload::default();
+        payload
+            .u64(world.tick)
+            .u32(world.snakes.len() as u32)
+            .u32(world.foods.len() as u32);
+        for snake in world.snakes.values() {
+            let velocity = snake.head_speed.map_or(Vec2::ZERO, |x| x.0);
+            payload
+                .i32(snake.player_id.0)
+                .u32(snake.alive as u32)
+                .f32(velocity.x)
+                .f32(velocity.y)
+                .f32(snake.head_radius.map_or(0.0, |x| x.0))
+                .f32(snake.score)
+                .u32(snake.body.len() as u32)
+                .name(
+                    snake
+                        .player_info
+                        .as_ref()
+                        .map(|x| x.username.as_str())
+                        .unwrap_or("unnamed"),
+                );
+            for node in snake.body.values() {
+                payload.f32(node.trans.0.x).f32(node.trans.0.y);
+            }
+        }
+        for food in &world.foods {
+            payload
+                .f32(food.pos.0.x)
+                .f32(food.pos.0.y)
+                .u32(food.value)
+                .u32(food.kind as u32);
+        }
+        payload.u32(world.kills.len() as u32);
+        for kill in &world.kills {
+            let cause = match kill.cause {
+                DeathCause::Wall => 0,
+                DeathCause::Body => 1,
+                DeathCause::HeadOn => 2,
+                DeathCause::SelfCollision => 3,
+                DeathCause::Obstacle => 4,
+            };
+            payload
+                .i32(kill.victim.0)
+                .i32(kill.killer.map_or(-1, |x| x.0))
+                .u32(cause);
+        }
+        payload.write_frame(out, TAG_MAP)
+    }
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()> {
+        Payload::default().write_frame(out, TAG_REQUEST_ACTION)
+    }
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()> {
+        let reason = match results.reason {
+            EndReason::TickLimit => 0,
+            EndReason::ScoreTarget => 1,
+            EndReason::LastSnakeStanding => 2,
+        };
+        let mut payload = Payload::default();
+        payload
+            .u32(results.round)
+            .u32(reason)
+            .u64(results.tick)
+            .i32(results.winner().map_or(-1, |x| x.player_id.0))
+            .u32(results.standings.len() as u32);
+        for standing in &results.standings {
+            payload
+                .u32(standing.rank)
+                .i32(standing.player_id.0)
+                .f32(standing.score)
+                .u32(standing.alive as u32)
+                .name(&standing.username);
+        }
+        payload.write_frame(out, TAG_GAME_OVER)
+    }
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        TextV1.parse_action(line)
+    }
+}
+
+/// Positions the game and a delta bot may disagree on before the game resends a snake
+const DELTA_TOLERANCE: f32 = 1e-3;
+
+/// What a bot speaking the delta protocol knows about a snake
+struct KnownSnake {
+    body: Vec<Position>,
+    alive: bool,
+    score: f32,
+}
+
+/// Version 5: version 2, but after a keyframe with the whole world only what changed is sent.
+/// The bot moves the segments itself with the rule of `Simulation::snake_move`, see the README.
+pub struct TextDelta {
+    edge_mode: EdgeMode,
+    resync_ticks: u64,
+    /// Tick of the last keyframe, `None` until the first one
+    keyframe_tick: Option<u64>,
+    /// Set when the bot asked for a keyframe
+    resync_requested: bool,
+    snakes: BTreeMap<PlayerId, KnownSnake>,
+    foods: BTreeMap<FoodId, Position>,
+}
+impl TextDelta {
+    pub fn new(config: &MatchConfig) -> Self {
+        Self {
+            edge_mode: config.edge_mode,
+            resync_ticks: config.resync_ticks,
+            keyframe_tick: None,
+            resync_requested: false,
+            snakes: Default::default(),
+            foods: Default::default(),
+        }
+    }
+    fn keyframe_due(&self, tick: u64) -> bool {
+        match self.keyframe_tick {
+            // a new round started since
+            Some(last) if tick < last => true,
+            Some(last) => self.resync_requested || tick >= last + self.resync_ticks,
+            None => true,
+        }
+    }
+    fn remember(&mut self, snake: &SnakeBody<Position>) {
+        self.snakes.insert(
+            snake.player_id,
+            KnownSnake {
+                body: snake.body.values().map(|node| node.trans).collect(),
+                alive: snake.alive,
+                score: snake.score,
+            },
+        );
+    }
+    /// Where the bot believes the segments are, once it moved them behind the new head
+    fn follow(&self, known: &[Position], head: Position) -> Vec<Position> {
+        let mut body = known.to_vec();
+        for i in (1..body.len()).rev() {
+            let d = delta(self.edge_mode, body[i], body[i - 1]);
+            body[i].0 += d * FOLLOW_RATE;
+        }
+        body[0] = head;
+        if self.edge_mode == EdgeMode::Wrap {
+            for node in &mut body {
+                node.0.x = wrap(node.0.x, ARENA_WIDTH);
+                node.0.y = wrap(node.0.y, ARENA_HEIGHT);
+            }
+        }
+        body
+    }
+    fn write_keyframe(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        writeln!(out, "keyframe")?;
+        self.snakes.clear();
+        self.foods.clear();
+        for snake in world.snakes.values() {
+            write_snake_v2(out, snake)?;
+            self.remember(snake);
+        }
+        for food in &world.foods {
+            writeln!(
+                out,
+                "food {} {} value {} kind {}",
+                food.id.0, food.pos, food.value, food.kind
+            )?;
+            self.foods.insert(food.id, food.pos);
+        }
+        self.keyframe_tick = Some(world.tick);
+        self.resync_requested = false;
+        Ok(())
+    }
+    fn write_delta(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        writeln!(out, "delta")?;
+        let gone: Vec<PlayerId> = self
+            .snakes
+            .keys()
+            .filter(|id| !world.snakes.contains_key(id))
+            .copied()
+            .collect();
+        for id in gone {
+            writeln!(out, "remove {}", id.0)?;
+            self.snakes.remove(&id);
+        }
+        for snake in world.snakes.values() {
+            let body: Vec<Position> = snake.body.values().map(|node| node.trans).collect();
+            let known = match self.snakes.get(&snake.player_id) {
+                Some(known) => known,
+                None => {
+                    write_snake_v2(out, snake)?;
+                    self.remember(snake);
+                    continue;
+                }
+            };
+            if !snake.alive {
+                if known.alive {
+                    writeln!(out, "death {}", snake.player_id.0)?;
+                    self.snakes.get_mut(&snake.player_id).unwrap().alive = false;
+                }
+                continue;
+            }
+            let followed = if known.alive && !body.is_empty() && body.len() >= known.body.len() {
+                Some(self.follow(&known.body, body[0]))
+            } else {
+                None
+            };
+            let mut followed = match followed {
+                Some(followed)
+                    if followed
+                        .iter()
+                        .zip(&body)
+                        .all(|(a, b)| (a.0 - b.0).abs().max_element() < DELTA_TOLERANCE) =>
+                {
+                    followed
+                }
+                _ => {
+                    // respawned, or moved in a way the bot can't work out
+                    write_snake_v2(out, snake)?;
+                    self.remember(snake);
+                    continue;
+                }
+            };
+            let velocity = snake.head_speed.map_or(Vec2::ZERO, |x| x.0);
+            writeln!(
+                out,
+                "head {} {} velocity ({},{})",
+                snake.player_id.0, body[0], velocity.x, velocity.y
+            )?;
+            for node in &body[followed.len()..] {
+                writeln!(out, "grow {} {}", snake.player_id.0, node)?;
+            }
+            followed.extend_from_slice(&body[followed.len()..]);
+            let known = self.snakes.get_mut(&snake.player_id).unwrap();
+            if known.score != snake.score {
+                writeln!(out, "score {} {}", snake.player_id.0, snake.score)?;
+                known.score = snake.score;
+            }
+            known.body = followed;
+        }
+        let eaten: Vec<FoodId> = self
+            .foods
+            .keys()
+            .filter(|id| world.foods.iter().all(|food| food.id != **id))
+            .copied()
+            .collect();
+        for id in eaten {
+            writeln!(out, "food_despawn {}", id.0)?;
+            self.foods.remove(&id);
+        }
+        for food in &world.foods {
+            if self.foods.insert(food.id, food.pos).is_none() {
+                writeln!(
+                    out,
+                    "food_spawn {} {} value {} kind {}",
+                    food.id.0, food.pos, food.value, food.kind
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Protocol for TextDelta {
+    fn version(&self) -> u32 {
+        5
+    }
+    fn write_map(&mut self, out: &mut dyn Write, world: &SnakeWorld) -> Result<()> {
+        writeln!(out, "MAP BEGIN")?;
+        writeln!(out, "tick {}", world.tick)?;
+        if self.keyframe_due(world.tick) {
+            self.write_keyframe(out, world)?;
+        } else {
+            self.write_delta(out, world)?;
+        }
+        write_kills(out, world)?;
+        writeln!(out, "MAP END")
+    }
+    fn write_request(&mut self, out: &mut dyn Write) -> Result<()> {
+        TextV1.write_request(out)
+    }
+    fn write_game_over(&mut self, out: &mut dyn Write, results: &MatchResults) -> Result<()> {
+        self.keyframe_tick = None;
+        TextV1.write_game_over(out, results)
+    }
+    /// A move, optionally followed by `resync` to get a keyframe with the next MAP.
+    /// `resync` alone goes straight.
+    fn parse_action(&mut self, line: &str) -> anyhow::Result<PlayerAction> {
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.contains(&"resync") {
+            self.resync_requested = true;
+            words.retain(|x| *x != "resync");
+        }
+        if words.is_empty() {
+            return Ok(MovementCommand::NoOps.into());
+        }
+        TextV1.parse_action(&words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{MovementCommand, PlayerAction};
+    use crate::simulation::running;
+    use std::collections::HashMap;
+
+    fn bodies(world: &SnakeWorld) -> BTreeMap<PlayerId, (bool, Vec<Position>)> {
+        world
+            .snakes
+            .values()
+            .map(|snake| {
+                let body = snake.body.values().map(|node| node.trans).collect();
+                (snake.player_id, (snake.alive, body))
+            })
+            .collect()
+    }
+
+    /// Every tick, moving the last known segments behind the new head must give the body
+    /// `Simulation::snake_move` made, or the game resends whole snakes all the time
+    fn follow_matches_snake_move(edge_mode: EdgeMode) {
+        let config = MatchConfig {
+            seed: Some(3),
+            edge_mode,
+            ..Default::default()
+        };
+        let delta = TextDelta::new(&config);
+        let mut sim = running(config, 4);
+        let mut followed = 0;
+        for tick in 0..1500 {
+            let before = bodies(&sim.world());
+            let mut commands = HashMap::new();
+            for i in 0..4 {
+                let movement = match (tick / 40 + i) % 3 {
+                    0 => MovementCommand::TurnLeft,
+                    1 => MovementCommand::TurnRight,
+                    _ => MovementCommand::NoOps,
+                };
+                commands.insert(PlayerId(i), PlayerAction::from(movement));
+            }
+            sim.step(&commands);
+            let world = sim.world();
+            for (id, (alive, body)) in bodies(&world) {
+                let (was_alive, known) = &before[&id];
+                let died = world.kills.iter().any(|kill| kill.victim == id);
+                if !alive || !was_alive || died || body.len() < known.len() {
+                    continue;
+                }
+                let guess = delta.follow(known, body[0]);
+                for (a, b) in guess.iter().zip(&body) {
+                    assert!(
+                        (a.0 - b.0).abs().max_element() < DELTA_TOLERANCE,
+                        "player {} at tick {}: followed to {}, moved to {}",
+                        id.0,
+                        tick,
+                        a,
+                        b
+                    );
+                }
+                followed += 1;
+            }
+        }
+        assert!(followed > 1000);
+    }
+
+    #[test]
+    fn actions_with_numbers_that_are_not_finite_are_rejected() {
+        for line in [
+            "face_towards nan 0",
+            "face_towards 0 inf",
+            "steer -inf",
+            "steer NaN",
+        ] {
+            let err = TextV1.parse_action(line).unwrap_err();
+            assert!(err.is::<ProtocolError>(), "{}", line);
+        }
+        assert!(TextV1.parse_action("face_towards 1.5 -2").is_ok());
+        let err = JsonLines
+            .parse_action(r#"{"action": "steer", "angle": 1e300}"#)
+            .unwrap_err();
+        assert!(err.is::<ProtocolError>());
+    }
+
+    #[test]
+    fn follow_matches_snake_move_with_walls() {
+        follow_matches_snake_move(EdgeMode::Wall);
+    }
+
+    #[test]
+    fn follow_matches_snake_move_across_wrapping_edges() {
+        follow_matches_snake_move(EdgeMode::Wrap);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::config::ScoreFormula;
+use crate::TICK;
+
+/// What a player did over the whole match. Unlike the length of its snake, it is kept when the
+/// snake dies and respawns, and from one round to the next.
+#[derive(Debug, Clone, Default)]
+pub struct PlayerScore {
+    /// Food worth segments, power-ups left out
+    pub food_eaten: u32,
+    /// Snakes that died running into this one
+    pub kills: u32,
+    pub deaths: u32,
+    /// Most segments the snake ever had
+    pub longest_length: usize,
+    /// Ticks spent alive while a round was running
+    pub survival_ticks: u64,
+}
+impl PlayerScore {
+    pub fn survival_seconds(&self) -> f32 {
+        self.survival_ticks as f32 * TICK
+    }
+    pub fn points(&self, formula: &ScoreFormula) -> f32 {
+        formula.food * self.food_eaten as f32
+            + formula.kill * self.kills as f32
+            + formula.death * self.deaths as f32
+            + formula.longest_length * self.longest_length as f32
+            + formula.survival * self.survival_seconds()
+    }
+}
@@ -0,0 +1,1290 @@
+use crate::config::{EdgeMode, FoodDistribution, HeadOn, MatchConfig};
+use crate::controller::{MovementCommand, PlayerAction, PlayerInfo};
+use crate::score::PlayerScore;
+use crate::{
+    FoodBody, FoodId, FoodKind, PlayerId, Position, Radius, SnakeBody, SnakeNode, SnakeWorld,
+    Velocity, ARENA_HEIGHT, ARENA_WIDTH, CONST_SPEED, GRID_SIZE, TICK,
+};
+use bevy::math::Vec2;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Formatter;
+
+/// Maximum turn rate of a snake head, in radians per second
+pub const OMEGA: f32 = 2.0 * std::f32::consts::PI;
+/// Angle a snake head turns by in a single tick
+pub const THETA: f32 = OMEGA * TICK;
+/// Distance the head of a snake travels in a second: its velocity has length `CONST_SPEED`
+/// and is scaled by `CONST_SPEED` again in `snake_move`
+pub const HEAD_SPEED: f32 = CONST_SPEED * CONST_SPEED;
+/// Radius of a snake head and of each of its segments
+pub const SNAKE_RADIUS: f32 = GRID_SIZE / 2.0;
+pub const FOOD_RADIUS: f32 = GRID_SIZE / 6.0;
+/// Share of the distance to the segment in front that a segment covers every tick
+pub const FOLLOW_RATE: f32 = 0.05;
+/// Head speed of a boosting snake, relative to `HEAD_SPEED`
+pub const BOOST_FACTOR: f32 = 2.0;
+/// A boosting snake drops its last segment as food every this many ticks
+pub const BOOST_BURN_TICKS: u32 = 15;
+/// Number of segments behind the head of a freshly spawned snake
+pub const INITIAL_SEGMENTS: usize = 3;
+/// Segments gained by eating large food
+pub const LARGE_FOOD_VALUE: u32 = 3;
+/// Segments lost by eating shrink food
+pub const SHRINK_SEGMENTS: usize = 2;
+/// Ticks that speed-up and ghost food last
+pub const EFFECT_TICKS: u32 = 300;
+/// How often each kind of food spawns, relative to the others
+pub const FOOD_KIND_WEIGHTS: [(FoodKind, u32); 5] = [
+    (FoodKind::Normal, 20),
+    (FoodKind::Large, 4),
+    (FoodKind::SpeedUp, 2),
+    (FoodKind::Shrink, 2),
+    (FoodKind::Ghost, 1),
+];
+
+/// SplitMix64. Small and fully specified, so a seed replays the same
+/// sequence on every platform and with every `rand` release.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+impl SimRng {
+    pub fn seed_from_u64(seed: u64) -> Self {
+        Self(seed)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    /// Uniformly distributed in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+pub struct Snake {
+    pub player_id: PlayerId,
+    pub player_info: Option<PlayerInfo>,
+    pub velocity: Velocity,
+    pub radius: Radius,
+    /// `body[0]` is the head
+    pub body: Vec<Position>,
+    /// False once the snake is out of the round. Its body stays as it was when it died.
+    pub alive: bool,
+    /// Tick at which the snake went out of the round
+    pub eliminated_at: Option<u64>,
+    pub boosting: bool,
+    /// Ticks of boosting since the last segment was burnt
+    boost_ticks: u32,
+    /// Ticks left of moving at boost speed after eating speed-up food
+    pub speed_up_ticks: u32,
+    /// Ticks left of passing through snakes after eating ghost food
+    pub ghost_ticks: u32,
+}
+impl Snake {
+    pub fn head(&self) -> Position {
+        self.body[0]
+    }
+    pub fn length(&self) -> u32 {
+        self.body.len() as u32
+    }
+    /// Whether the head touches a segment further back along the body than `grace`
+    fn hits_itself(&self, mode: EdgeMode, grace: f32) -> bool {
+        let head = self.head();
+        let mut along = 0.0;
+        for i in 1..self.body.len() {
+            along += distance(mode, self.body[i - 1], self.body[i]);
+            if along > grace && distance(mode, head, self.body[i]) < 2.0 * self.radius.0 {
+                return true;
+            }
+        }
+        false
+    }
+    /// Snakes don't burn segments below their starting length
+    pub fn can_boost(&self) -> bool {
+        self.body.len() > INITIAL_SEGMENTS + 1
+    }
+    /// Velocity the head actually moves at
+    pub fn head_velocity(&self) -> Velocity {
+        if self.boosting || self.speed_up_ticks > 0 {
+            Velocity(self.velocity.0 * BOOST_FACTOR)
+        } else {
+            self.velocity
+        }
+    }
+    fn respawn(&mut self, rng: &mut SimRng, pos: Position) {
+        self.velocity = Velocity::random(rng, CONST_SPEED);
+        self.body = vec![pos; INITIAL_SEGMENTS + 1];
+        self.alive = true;
+        self.eliminated_at = None;
+        self.boosting = false;
+        self.boost_ticks = 0;
+        self.speed_up_ticks = 0;
+        self.ghost_ticks = 0;
+    }
+    /// Applies what eating `food` does
+    fn eat(&mut self, food: &FoodPellet) {
+        // grows at the tail, so that the head can't run into its new segments
+        let tail = *self.body.last().unwrap();
+        for _ in 0..food.value {
+            self.body.push(tail);
+        }
+        match food.kind {
+            FoodKind::Normal | FoodKind::Large => {}
+            FoodKind::SpeedUp => self.speed_up_ticks = EFFECT_TICKS,
+            FoodKind::Shrink => {
+                let len = self.body.len().saturating_sub(SHRINK_SEGMENTS);
+                self.body.truncate(len.max(INITIAL_SEGMENTS + 1));
+            }
+            FoodKind::Ghost => self.ghost_ticks = EFFECT_TICKS,
+        }
+    }
+    /// Respawns the snake at `respawn_at`, or takes it out of the round when snakes don't
+    /// respawn
+    fn die(&mut self, rng: &mut SimRng, respawn_at: Option<Position>, tick: u64) {
+        match respawn_at {
+            Some(pos) => self.respawn(rng, pos),
+            None => {
+                self.alive = false;
+                self.eliminated_at = Some(tick);
+            }
+        }
+    }
+}
+
+pub struct FoodPellet {
+    pub id: FoodId,
+    pub kind: FoodKind,
+    pub pos: Position,
+    pub radius: Radius,
+    /// Segments gained by eating it
+    pub value: u32,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MatchPhase {
+    /// Players are joining, nothing moves
+    Lobby,
+    /// The round starts after `remaining` more ticks
+    Countdown {
+        remaining: u64,
+    },
+    Running,
+    /// The round is over, see [`Simulation::results`]
+    Finished,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EndReason {
+    TickLimit,
+    ScoreTarget,
+    LastSnakeStanding,
+}
+impl std::fmt::Display for EndReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EndReason::TickLimit => "tick_limit",
+            EndReason::ScoreTarget => "score_target",
+            EndReason::LastSnakeStanding => "last_snake_standing",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Standing {
+    /// 1 for the winner. Players that can't be told apart share a rank.
+    pub rank: u32,
+    pub player_id: PlayerId,
+    pub username: String,
+    /// Points by the scoring formula
+    pub score: f32,
+    pub alive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchResults {
+    pub round: u32,
+    /// Ticks the round lasted
+    pub tick: u64,
+    pub reason: EndReason,
+    /// Best first
+    pub standings: Vec<Standing>,
+}
+impl MatchResults {
+    /// `None` if several players share the first rank
+    pub fn winner(&self) -> Option<&Standing> {
+        match self.standings.as_slice() {
+            [first, second, ..] if second.rank == first.rank => None,
+            [first, ..] => Some(first),
+            [] => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeathCause {
+    Wall,
+    /// Ran into the body of another snake
+    Body,
+    HeadOn,
+    SelfCollision,
+    /// Ran into an obstacle of the map
+    Obstacle,
+}
+impl std::fmt::Display for DeathCause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DeathCause::Wall => "wall",
+            DeathCause::Body => "body",
+            DeathCause::HeadOn => "head_on",
+            DeathCause::SelfCollision => "self_collision",
+            DeathCause::Obstacle => "obstacle",
+        })
+    }
+}
+/// A snake died
+#[derive(Debug, Copy, Clone)]
+pub struct KillEvent {
+    pub tick: u64,
+    pub victim: PlayerId,
+    /// The snake the victim ran into, if another one
+    pub killer: Option<PlayerId>,
+    pub cause: DeathCause,
+}
+
+/// The whole game state and rules, without any rendering.
+///
+/// One call to [`Simulation::step`] advances the game by one `TICK`.
+pub struct Simulation {
+    pub config: MatchConfig,
+    /// Seed the RNG was created from, logged so any match can be replayed
+    pub seed: u64,
+    pub phase: MatchPhase,
+    /// Starts at 1
+    pub round: u32,
+    /// Ticks played in the current round
+    pub tick: u64,
+    /// Set when the current round is finished
+    pub results: Option<MatchResults>,
+    pub snakes: BTreeMap<PlayerId, Snake>,
+    pub foods: Vec<FoodPellet>,
+    /// Deaths of the last tick
+    pub kills: Vec<KillEvent>,
+    /// Kept for the whole match, even for players that left
+    pub scores: BTreeMap<PlayerId, PlayerScore>,
+    /// Points of each player when the current round started, the score target counts from there
+    round_start_points: BTreeMap<PlayerId, f32>,
+    /// Centres of the clustered food distribution in this round, picked with the first food
+    food_clusters: Vec<Position>,
+    rng: SimRng,
+    next_food_id: u64,
+}
+/// Maps `v` into `[-size / 2, size / 2)`
+pub(crate) fn wrap(v: f32, size: f32) -> f32 {
+    (v + size / 2.0).rem_euclid(size) - size / 2.0
+}
+/// Vector from `from` to `to`. It takes the shortest way through the edges if the arena wraps.
+pub(crate) fn delta(mode: EdgeMode, from: Position, to: Position) -> Vec2 {
+    let d = to.0 - from.0;
+    match mode {
+        EdgeMode::Wrap => Vec2::new(wrap(d.x, ARENA_WIDTH), wrap(d.y, ARENA_HEIGHT)),
+        EdgeMode::Wall | EdgeMode::Bounce => d,
+    }
+}
+fn distance(mode: EdgeMode, a: Position, b: Position) -> f32 {
+    delta(mode, a, b).length()
+}
+fn rotate(v: Vec2, theta: f32) -> Vec2 {
+    let (sin, cos) = theta.sin_cos();
+    Vec2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+impl Simulation {
+    pub fn new(config: MatchConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        Self {
+            config,
+            seed,
+            phase: MatchPhase::Lobby,
+            round: 1,
+            tick: 0,
+            results: None,
+            snakes: Default::default(),
+            foods: vec![],
+            kills: vec![],
+            scores: Default::default(),
+            round_start_points: Default::default(),
+            food_clusters: vec![],
+            rng: SimRng::seed_from_u64(seed),
+            next_food_id: 0,
+        }
+    }
+    /// Adds a player and spawns its snake, see [`Simulation::spawn_position`]
+    pub fn add_player(&mut self, player_id: PlayerId, info: PlayerInfo) {
+        let mut snake = Snake {
+            player_id,
+            player_info: Some(info),
+            velocity: Velocity(Vec2::ZERO),
+            radius: Radius(SNAKE_RADIUS),
+            body: vec![],
+            alive: true,
+            eliminated_at: None,
+            boosting: false,
+            boost_ticks: 0,
+            speed_up_ticks: 0,
+            ghost_ticks: 0,
+        };
+        let pos = self.spawn_position(player_id);
+        snake.respawn(&mut self.rng, pos);
+        let score = self.scores.entry(player_id).or_default();
+        score.longest_length = score.longest_length.max(snake.body.len());
+        self.snakes.insert(player_id, snake);
+        // joining in the middle of a round, only what comes next counts
+        let points = self.points(player_id);
+        self.round_start_points.insert(player_id, points);
+    }
+    pub fn remove_player(&mut self, player_id: PlayerId) -> Option<Snake> {
+        self.snakes.remove(&player_id)
+    }
+    /// Where the snake of `player` (re)spawns: a spawn point of the map away from the other
+    /// heads, or a random place clear of obstacles when the map has none
+    fn spawn_position(&mut self, player: PlayerId) -> Position {
+        const TRIES: u32 = 16;
+        let mode = self.config.edge_mode;
+        let arena = &self.config.arena;
+        let heads: Vec<Position> = self
+            .snakes
+            .values()
+            .filter(|snake| snake.alive && snake.player_id != player)
+            .map(|snake| snake.head())
+            .collect();
+        let clearance = |pos: Position| {
+            heads
+                .iter()
+                .map(|head| distance(mode, *head, pos))
+                .fold(f32::INFINITY, f32::min)
+        };
+        if !arena.spawn_points.is_empty() {
+            let points: Vec<Position> = arena
+                .spawn_points
+                .iter()
+                .map(|[x, y]| Position(Vec2::new(*x, *y)))
+                .collect();
+            let free: Vec<Position> = points
+                .iter()
+                .copied()
+                .filter(|pos| clearance(*pos) >= 4.0 * SNAKE_RADIUS)
+                .collect();
+            if free.is_empty() {
+                // all taken, the least crowded one then
+                return points
+                    .into_iter()
+                    .max_by(|a, b| clearance(*a).total_cmp(&clearance(*b)))
+                    .unwrap();
+            }
+            return free[(self.rng.next_u64() % free.len() as u64) as usize];
+        }
+        let mut pos = Position::random(&mut self.rng, ARENA_WIDTH, ARENA_HEIGHT);
+        for _ in 1..TRIES {
+            if arena.obstacle_distance(pos.0) >= 2.0 * SNAKE_RADIUS {
+                break;
+            }
+            pos = Position::random(&mut self.rng, ARENA_WIDTH, ARENA_HEIGHT);
+        }
+        pos
+    }
+    /// Spawns food of `kind` worth the usual number of segments for it.
+    pub fn spawn_food(&mut self, pos: Position, kind: FoodKind) -> FoodId {
+        let value = match kind {
+            FoodKind::Normal => 1,
+            FoodKind::Large => LARGE_FOOD_VALUE,
+            FoodKind::SpeedUp | FoodKind::Shrink | FoodKind::Ghost => 0,
+        };
+        self.spawn_food_worth(pos, kind, value)
+    }
+    pub fn spawn_food_worth(&mut self, pos: Position, kind: FoodKind, value: u32) -> FoodId {
+        let id = FoodId(self.next_food_id);
+        self.next_food_id += 1;
+        let radius = match kind {
+            FoodKind::Large => 2.0 * FOOD_RADIUS,
+            _ => FOOD_RADIUS,
+        };
+        self.foods.push(FoodPellet {
+            id,
+            kind,
+            pos,
+            radius: Radius(radius),
+            value,
+        });
+        id
+    }
+
+    /// Leaves the lobby and starts the countdown of the first round
+    pub fn start(&mut self) {
+        if self.phase == MatchPhase::Lobby {
+            self.start_countdown();
+        }
+    }
+    /// Starts another round with the same players, once the current one is finished
+    pub fn next_round(&mut self) {
+        if self.phase != MatchPhase::Finished {
+            return;
+        }
+        self.round += 1;
+        self.tick = 0;
+        self.results = None;
+        self.foods.clear();
+        self.food_clusters.clear();
+        self.kills.clear();
+        let players: Vec<PlayerId> = self.snakes.keys().copied().collect();
+        for player in players {
+            let pos = self.spawn_position(player);
+            self.snakes
+                .get_mut(&player)
+                .unwrap()
+                .respawn(&mut self.rng, pos);
+        }
+        self.start_countdown();
+    }
+    fn start_countdown(&mut self) {
+        self.round_start_points = self
+            .snakes
+            .keys()
+            .map(|player| (*player, self.points(*player)))
+            .collect();
+        self.phase = match self.config.countdown_ticks {
+            0 => MatchPhase::Running,
+            remaining => MatchPhase::Countdown { remaining },
+        };
+    }
+
+    /// Advances the game by one tick. Players without a command go straight.
+    /// Nothing moves unless the round is running.
+    pub fn step(&mut self, commands: &HashMap<PlayerId, PlayerAction>) {
+        match self.phase {
+            MatchPhase::Lobby | MatchPhase::Finished => {}
+            MatchPhase::Countdown { remaining } => {
+                self.phase = match remaining {
+                    1 => MatchPhase::Running,
+                    _ => MatchPhase::Countdown {
+                        remaining: remaining - 1,
+                    },
+                };
+            }
+            MatchPhase::Running => {
+                self.kills.clear();
+                self.process_movement(commands);
+                self.snake_move();
+                self.burn_boost();
+                self.enforce_edges();
+                self.eat_food_and_extend();
+                self.death_detection();
+                for snake in self.snakes.values().filter(|snake| snake.alive) {
+                    self.scores
+                        .entry(snake.player_id)
+                        .or_default()
+                        .survival_ticks += 1;
+                }
+                self.food_spawner();
+                self.tick += 1;
+                if let Some(reason) = self.end_reason() {
+                    self.results = Some(self.standings(reason));
+                    self.phase = MatchPhase::Finished;
+                }
+            }
+        }
+    }
+    /// Points of `player` by the scoring formula
+    pub fn points(&self, player: PlayerId) -> f32 {
+        self.scores
+            .get(&player)
+            .map_or(0.0, |score| score.points(&self.config.scoring))
+    }
+    /// Points `player` made since the current round started
+    pub fn round_points(&self, player: PlayerId) -> f32 {
+        let start = self.round_start_points.get(&player).copied().unwrap_or(0.0);
+        self.points(player) - start
+    }
+    /// Players in the game with their points by the scoring formula, best first
+    pub fn leaderboard(&self) -> Vec<(PlayerId, f32)> {
+        let mut board: Vec<(PlayerId, f32)> = self
+            .snakes
+            .keys()
+            .map(|player| (*player, self.points(*player)))
+            .collect();
+        board.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        board
+    }
+    fn end_reason(&self) -> Option<EndReason> {
+        let end = &self.config.end;
+        if end.last_snake_standing {
+            let alive = self.snakes.values().filter(|snake| snake.alive).count();
+            // a lone player plays until it dies
+            if alive == 0 || (alive == 1 && self.snakes.len() > 1) {
+                return Some(EndReason::LastSnakeStanding);
+            }
+        }
+        if let Some(target) = end.score_target {
+            if self
+                .snakes
+                .keys()
+                .any(|player| self.round_points(*player) >= target)
+            {
+                return Some(EndReason::ScoreTarget);
+            }
+        }
+        if let Some(limit) = end.tick_limit {
+            if self.tick >= limit {
+                return Some(EndReason::TickLimit);
+            }
+        }
+        None
+    }
+    fn standings(&self, reason: EndReason) -> MatchResults {
+        // survivors first, then whoever lasted longer, then the higher score
+        let key = |snake: &Snake| {
+            (
+                snake.alive,
+                snake.eliminated_at.unwrap_or(u64::MAX),
+                self.points(snake.player_id),
+            )
+        };
+        let order = |a: &Snake, b: &Snake| {
+            let (a, b) = (key(a), key(b));
+            (a.0, a.1).cmp(&(b.0, b.1)).then(a.2.total_cmp(&b.2))
+        };
+        let mut snakes: Vec<&Snake> = self.snakes.values().collect();
+        snakes.sort_by(|a, b| order(b, a));
+        let mut standings: Vec<Standing> = vec![];
+        for (i, snake) in snakes.iter().enumerate() {
+            let rank = match standings.last() {
+                Some(last) if key(snakes[i - 1]) == key(snake) => last.rank,
+                _ => i as u32 + 1,
+            };
+            standings.push(Standing {
+                rank,
+                player_id: snake.player_id,
+                username: snake
+                    .player_info
+                    .as_ref()
+                    .map(|x| x.username.clone())
+                    .unwrap_or_default(),
+                score: self.points(snake.player_id),
+                alive: snake.alive,
+            });
+        }
+        MatchResults {
+            round: self.round,
+            tick: self.tick,
+            reason,
+            standings,
+        }
+    }
+    fn process_movement(&mut self, commands: &HashMap<PlayerId, PlayerAction>) {
+        let mode = self.config.edge_mode;
+        for snake in self.snakes.values_mut() {
+            snake.boosting = false;
+        }
+        for (player, action) in commands {
+            if let Some(snake) = self.snakes.get_mut(player) {
+                snake.boosting = action.boost && snake.can_boost();
+                let v = snake.velocity.0;
+                let angle = match action.movement {
+                    MovementCommand::TurnLeft => THETA,
+                    MovementCommand::TurnRight => -THETA,
+                    MovementCommand::NoOps => 0.0,
+                    MovementCommand::Steer(angle) => angle,
+                    MovementCommand::FaceTowards(target) => {
+                        let d = delta(mode, snake.head(), target);
+                        if d == Vec2::ZERO || v == Vec2::ZERO {
+                            0.0
+                        } else {
+                            (v.x * d.y - v.y * d.x).atan2(v.dot(d))
+                        }
+                    }
+                };
+                // a NaN would stay in the velocity, and the position, for good
+                let angle = if angle.is_finite() { angle } else { 0.0 };
+                snake.velocity = Velocity(rotate(v, angle.clamp(-THETA, THETA)));
+            }
+        }
+    }
+    fn snake_move(&mut self) {
+        let mode = self.config.edge_mode;
+        for snake in self.snakes.values_mut().filter(|snake| snake.alive) {
+            let velocity = snake.head_velocity();
+            let body = &mut snake.body;
+            for i in (1..body.len()).rev() {
+                let d = delta(mode, body[i], body[i - 1]);
+                body[i].0 += d * FOLLOW_RATE;
+            }
+            body[0].0 += CONST_SPEED * TICK * velocity.0;
+            snake.speed_up_ticks = snake.speed_up_ticks.saturating_sub(1);
+            snake.ghost_ticks = snake.ghost_ticks.saturating_sub(1);
+        }
+    }
+    /// Boosting snakes lose their last segment every `BOOST_BURN_TICKS`, left behind as food
+    fn burn_boost(&mut self) {
+        let mut burnt = vec![];
+        for snake in self.snakes.values_mut().filter(|snake| snake.alive) {
+            if !snake.boosting {
+                continue;
+            }
+            snake.boost_ticks += 1;
+            if snake.boost_ticks >= BOOST_BURN_TICKS {
+                snake.boost_ticks = 0;
+                burnt.push(snake.body.pop().unwrap());
+            }
+        }
+        for pos in burnt {
+            self.spawn_food(pos, FoodKind::Normal);
+        }
+    }
+    /// Kills a snake, crediting `killer`. Its body is left behind as food.
+    fn kill(&mut self, victim: PlayerId, killer: Option<PlayerId>, cause: DeathCause) {
+        if !self.snakes.contains_key(&victim) {
+            return;
+        }
+        let respawn_at = if self.config.respawn() {
+            Some(self.spawn_position(victim))
+        } else {
+            None
+        };
+        if let Some(snake) = self.snakes.get_mut(&victim) {
+            let corpse = snake.body.clone();
+            snake.die(&mut self.rng, respawn_at, self.tick);
+            let value = self.config.corpse_food_value;
+            if value > 0 {
+                for pos in corpse {
+                    self.spawn_food_worth(pos, FoodKind::Normal, value);
+                }
+            }
+            self.scores.entry(victim).or_default().deaths += 1;
+            if let Some(killer) = killer {
+                self.scores.entry(killer).or_default().kills += 1;
+            }
+            self.kills.push(KillEvent {
+                tick: self.tick,
+                victim,
+                killer,
+                cause,
+            });
+        }
+    }
+    fn enforce_edges(&mut self) {
+        let (half_x, half_y) = (ARENA_WIDTH / 2.0, ARENA_HEIGHT / 2.0);
+        let mut walled = vec![];
+        for snake in self.snakes.values_mut().filter(|snake| snake.alive) {
+            match self.config.edge_mode {
+                EdgeMode::Wall => {
+                    let head = snake.head().0;
+                    if head.x.abs() > half_x || head.y.abs() > half_y {
+                        walled.push(snake.player_id);
+                    }
+                }
+                EdgeMode::Wrap => {
+                    for node in snake.body.iter_mut() {
+                        node.0 =
+                            Vec2::new(wrap(node.0.x, ARENA_WIDTH), wrap(node.0.y, ARENA_HEIGHT));
+                    }
+                }
+                EdgeMode::Bounce => {
+                    let head = &mut snake.body[0].0;
+                    let vel = &mut snake.velocity.0;
+                    if head.x.abs() > half_x {
+                        head.x = head.x.clamp(-half_x, half_x);
+                        vel.x = -vel.x;
+                    }
+                    if head.y.abs() > half_y {
+                        head.y = head.y.clamp(-half_y, half_y);
+                        vel.y = -vel.y;
+                    }
+                }
+            }
+        }
+        for victim in walled {
+            self.kill(victim, None, DeathCause::Wall);
+        }
+    }
+    fn eat_food_and_extend(&mut self) {
+        let mode = self.config.edge_mode;
+        for snake in self.snakes.values_mut().filter(|snake| snake.alive) {
+            let head = snake.head();
+            let eaten = self
+                .foods
+                .iter()
+                .position(|food| distance(mode, head, food.pos) < snake.radius.0 + food.radius.0);
+            if let Some(index) = eaten {
+                let food = self.foods.remove(index);
+                snake.eat(&food);
+                let score = self.scores.entry(snake.player_id).or_default();
+                // power-ups give no segments, and no points
+                if food.value > 0 {
+                    score.food_eaten += 1;
+                }
+                score.longest_length = score.longest_length.max(snake.body.len());
+            }
+        }
+    }
+    fn death_detection(&mut self) {
+        let mode = self.config.edge_mode;
+        let rules = &self.config.collisions;
+        let mut deaths: Vec<(PlayerId, Option<PlayerId>, DeathCause)> = vec![];
+        for snake in self.snakes.values().filter(|snake| snake.alive) {
+            if self.config.arena.obstacle_distance(snake.head().0) < snake.radius.0 {
+                deaths.push((snake.player_id, None, DeathCause::Obstacle));
+            }
+        }
+        // ghosts neither hit nor get hit
+        let alive: Vec<&Snake> = self
+            .snakes
+            .values()
+            .filter(|snake| snake.alive && snake.ghost_ticks == 0)
+            .collect();
+        let mut head_on = vec![];
+        for (i, a) in alive.iter().enumerate() {
+            for b in &alive[i + 1..] {
+                if distance(mode, a.head(), b.head()) >= a.radius.0 + b.radius.0 {
+                    continue;
+                }
+                head_on.push((a.player_id, b.player_id));
+                let (a_dies, b_dies) = match rules.head_on {
+                    HeadOn::BothDie => (true, true),
+                    HeadOn::LongerWins => match a.body.len().cmp(&b.body.len()) {
+                        Ordering::Greater => (false, true),
+                        Ordering::Less => (true, false),
+                        Ordering::Equal => (true, true),
+                    },
+                    HeadOn::Random => {
+                        let a_dies = self.rng.next_u64() & 1 == 0;
+                        (a_dies, !a_dies)
+                    }
+                };
+                if a_dies {
+                    deaths.push((a.player_id, Some(b.player_id), DeathCause::HeadOn));
+                }
+                if b_dies {
+                    deaths.push((b.player_id, Some(a.player_id), DeathCause::HeadOn));
+                }
+            }
+        }
+        for snake in &alive {
+            if rules.self_collision && snake.hits_itself(mode, rules.self_collision_grace) {
+                deaths.push((snake.player_id, None, DeathCause::SelfCollision));
+                continue;
+            }
+            for other in &alive {
+                let pair = (snake.player_id, other.player_id);
+                // heads that met were settled above, even if they touched a neck too
+                if other.player_id == snake.player_id
+                    || head_on.contains(&pair)
+                    || head_on.contains(&(pair.1, pair.0))
+                {
+                    continue;
+                }
+                let reach = snake.radius.0 + other.radius.0;
+                let collision = other.body[1..]
+                    .iter()
+                    .any(|node| distance(mode, snake.head(), *node) < reach);
+                if collision {
+                    deaths.push((snake.player_id, Some(other.player_id), DeathCause::Body));
+                    break;
+                }
+            }
+        }
+        let mut dead = HashSet::new();
+        for (victim, killer, cause) in deaths {
+            if dead.insert(victim) {
+                self.kill(victim, killer, cause);
+            }
+        }
+    }
+    fn food_spawner(&mut self) {
+        let policy = &self.config.food_spawn;
+        if policy.interval_ticks == 0 || (self.tick + 1) % policy.interval_ticks != 0 {
+            return;
+        }
+        // only caps spawned food, what dead or boosting snakes leave behind is always added
+        if let Some(max_count) = policy.max_count {
+            if self.foods.len() >= max_count {
+                return;
+            }
+        }
+        if let Some(density) = policy.target_density {
+            let target = (density * ARENA_WIDTH * ARENA_HEIGHT / 1000.0) as usize;
+            if self.foods.len() >= target {
+                return;
+            }
+        }
+        if let Some(pos) = self.food_spawn_position() {
+            let kind = self.random_food_kind();
+            self.spawn_food(pos, kind);
+        }
+    }
+    /// A place for food picked by the spawn policy, clear of snakes.
+    /// `None` if none was found within a few tries.
+    fn food_spawn_position(&mut self) -> Option<Position> {
+        const TRIES: u32 = 16;
+        let mode = self.config.edge_mode;
+        let policy = &self.config.food_spawn;
+        let arena = &self.config.arena;
+        let (half_x, half_y) = (ARENA_WIDTH / 2.0, ARENA_HEIGHT / 2.0);
+        // with the radius food lands within around each
+        let centres: Vec<(Position, f32)> = match policy.distribution {
+            // the food zones of the map take over
+            _ if !arena.food_zones.is_empty() => arena
+                .food_zones
+                .iter()
+                .map(|zone| (Position(Vec2::new(zone.x, zone.y)), zone.radius))
+                .collect(),
+            FoodDistribution::Uniform => vec![],
+            FoodDistribution::Clustered => {
+                if self.food_clusters.is_empty() {
+                    for _ in 0..policy.clusters {
+                        let centre = Position::random(&mut self.rng, ARENA_WIDTH, ARENA_HEIGHT);
+                        self.food_clusters.push(centre);
+                    }
+                }
+                self.food_clusters
+                    .iter()
+                    .map(|centre| (*centre, policy.spread))
+                    .collect()
+            }
+            FoodDistribution::Hotspots => policy
+                .hotspots
+                .iter()
+                .map(|[x, y]| (Position(Vec2::new(*x, *y)), policy.spread))
+                .collect(),
+        };
+        for _ in 0..TRIES {
+            let pos = if centres.is_empty() {
+                Position::random(&mut self.rng, ARENA_WIDTH, ARENA_HEIGHT)
+            } else {
+                let (centre, spread) =
+                    centres[(self.rng.next_u64() % centres.len() as u64) as usize];
+                let angle = 2.0 * std::f32::consts::PI * self.rng.next_f32();
+                // uniform over the disc around the centre
+                let r = spread * self.rng.next_f32().sqrt();
+                let p = centre.0 + r * Vec2::new(angle.cos(), angle.sin());
+                Position(Vec2::new(
+                    p.x.clamp(-half_x, half_x),
+                    p.y.clamp(-half_y, half_y),
+                ))
+            };
+            let free =
+                self.snakes
+                    .values()
+                    .filter(|snake| snake.alive)
+                    .all(|snake| {
+                        distance(mode, snake.head(), pos) >= policy.head_exclusion_radius
+                            && snake.body.iter().all(|node| {
+                                distance(mode, *node, pos) >= snake.radius.0 + FOOD_RADIUS
+                            })
+                    });
+            if free && arena.obstacle_distance(pos.0) >= FOOD_RADIUS {
+                return Some(pos);
+            }
+        }
+        None
+    }
+    fn random_food_kind(&mut self) -> FoodKind {
+        let total: u32 = FOOD_KIND_WEIGHTS.iter().map(|(_, weight)| weight).sum();
+        let mut pick = (self.rng.next_u64() % total as u64) as u32;
+        for (kind, weight) in FOOD_KIND_WEIGHTS {
+            if pick < weight {
+                return kind;
+            }
+            pick -= weight;
+        }
+        FoodKind::Normal
+    }
+
+    /// Snapshot of the game handed to the controllers. Snakes that are out of the round are
+    /// in it too, with `alive` false.
+    pub fn world(&self) -> SnakeWorld {
+        let mut world = SnakeWorld {
+            tick: self.tick,
+            ..Default::default()
+        };
+        for food in &self.foods {
+            world.foods.push(FoodBody {
+                id: food.id,
+                kind: food.kind,
+                pos: food.pos,
+                value: food.value,
+            });
+        }
+        for (player, snake) in &self.snakes {
+            let body = snake
+                .body
+                .iter()
+                .enumerate()
+                .map(|(i, pos)| {
+                    (
+                        i as i32,
+                        SnakeNode {
+                            seg_id: i as i32,
+                            trans: *pos,
+                            entity: None,
+                        },
+                    )
+                })
+                .collect();
+            world.snakes.insert(
+                *player,
+                SnakeBody {
+                    player_id: *player,
+                    player_info: snake.player_info.clone(),
+                    head_speed: Some(snake.head_velocity()),
+                    head_radius: Some(snake.radius),
+                    score: self.points(*player),
+                    alive: snake.alive,
+                    body,
+                },
+            );
+        }
+        world.kills = self.kills.clone();
+        world
+    }
+}
+
+/// A match of `players` AIs, running from the first tick, seeded with 7 unless `config` has a
+/// seed
+#[cfg(test)]
+pub(crate) fn running(config: MatchConfig, players: i32) -> Simulation {
+    let mut sim = Simulation::new(MatchConfig {
+        seed: config.seed.or(Some(7)),
+        countdown_ticks: 0,
+        ..config
+    });
+    for i in 0..players {
+        let info = PlayerInfo {
+            username: format!("p{}", i),
+            is_ai: true,
+            color: None,
+        };
+        sim.add_player(PlayerId(i), info);
+    }
+    sim.start();
+    sim
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CollisionRules;
+
+    /// Puts the snake of `player` at `head`, all its segments on top of each other
+    fn place(sim: &mut Simulation, player: i32, head: Vec2, velocity: Vec2, segments: usize) {
+        let snake = sim.snakes.get_mut(&PlayerId(player)).unwrap();
+        snake.body = vec![Position(head); segments];
+        snake.velocity = Velocity(velocity);
+    }
+    fn causes(sim: &Simulation) -> Vec<(PlayerId, Option<PlayerId>, DeathCause)> {
+        let mut kills: Vec<_> = sim
+            .kills
+            .iter()
+            .map(|kill| (kill.victim, kill.killer, kill.cause))
+            .collect();
+        kills.sort_by_key(|kill| kill.0);
+        kills
+    }
+
+    /// Everything a controller can see of the match
+    fn snapshot(sim: &Simulation) -> String {
+        let world = sim.world();
+        let mut out = format!("tick {}\n", world.tick);
+        for snake in world.snakes.values() {
+            out += &format!(
+                "{} {:?} {} {}",
+                snake.player_id.0, snake.head_speed, snake.alive, snake.score
+            );
+            for node in snake.body.values() {
+                out += &format!(" {}", node.trans);
+            }
+            out += "\n";
+        }
+        for food in &world.foods {
+            out += &format!(
+                "food {} {} {} {}\n",
+                food.id.0, food.kind, food.pos, food.value
+            );
+        }
+        out
+    }
+
+    #[test]
+    fn same_seed_and_inputs_replay_the_same_match() {
+        let config = MatchConfig {
+            edge_mode: EdgeMode::Bounce,
+            ..Default::default()
+        };
+        let mut a = running(config.clone(), 3);
+        let mut b = running(config, 3);
+        for tick in 0..2000 {
+            let mut commands = HashMap::new();
+            let movement = match tick % 7 {
+                0 | 1 => MovementCommand::TurnLeft,
+                2 => MovementCommand::TurnRight,
+                3 => MovementCommand::Steer(0.01),
+                4 => MovementCommand::FaceTowards(Position(Vec2::new(10.0, -20.0))),
+                _ => MovementCommand::NoOps,
+            };
+            commands.insert(
+                PlayerId(tick % 3),
+                PlayerAction {
+                    movement,
+                    boost: tick % 5 == 0,
+                },
+            );
+            a.step(&commands);
+            b.step(&commands);
+            assert_eq!(snapshot(&a), snapshot(&b), "diverged at tick {}", tick);
+        }
+    }
+
+    #[test]
+    fn different_seeds_play_different_matches() {
+        let mut a = running(MatchConfig::default(), 2);
+        let config = MatchConfig {
+            seed: Some(8),
+            ..Default::default()
+        };
+        let mut b = running(config, 2);
+        a.step(&HashMap::new());
+        b.step(&HashMap::new());
+        assert_ne!(snapshot(&a), snapshot(&b));
+    }
+
+    #[test]
+    fn facing_a_point_that_is_not_finite_goes_straight() {
+        let mut sim = running(MatchConfig::default(), 1);
+        place(&mut sim, 0, Vec2::ZERO, Vec2::new(3.0, 4.0), 3);
+        let target = Position(Vec2::new(f32::NAN, 0.0));
+        let mut commands = HashMap::new();
+        commands.insert(
+            PlayerId(0),
+            PlayerAction::from(MovementCommand::FaceTowards(target)),
+        );
+        sim.step(&commands);
+        let snake = &sim.snakes[&PlayerId(0)];
+        assert_eq!(snake.velocity.0, Vec2::new(3.0, 4.0));
+        assert!(snake.head().0.is_finite());
+    }
+
+    #[test]
+    fn points_not_length_reach_the_score_target_and_rank_players() {
+        let mut config = MatchConfig::default();
+        config.end.score_target = Some(10.0);
+        let mut sim = running(config, 2);
+        sim.scores.entry(PlayerId(1)).or_default().kills = 1;
+        sim.step(&HashMap::new());
+        let results = sim.results.as_ref().expect("the round should be over");
+        assert_eq!(results.reason, EndReason::ScoreTarget);
+        let standings: Vec<_> = results
+            .standings
+            .iter()
+            .map(|standing| (standing.player_id, standing.score))
+            .collect();
+        assert_eq!(standings, [(PlayerId(1), 10.0), (PlayerId(0), 0.0)]);
+        let world = sim.world();
+        assert_eq!(world.snakes[&PlayerId(1)].score, 10.0);
+    }
+
+    #[test]
+    fn the_score_target_counts_the_points_of_the_round_only() {
+        let mut config = MatchConfig::default();
+        config.end.score_target = Some(10.0);
+        let mut sim = running(config, 2);
+        sim.scores.entry(PlayerId(1)).or_default().kills = 1;
+        sim.step(&HashMap::new());
+        assert_eq!(sim.phase, MatchPhase::Finished);
+        sim.next_round();
+        for _ in 0..10 {
+            sim.step(&HashMap::new());
+        }
+        assert_eq!(sim.phase, MatchPhase::Running);
+        assert_eq!(sim.points(PlayerId(1)), 10.0);
+        sim.scores.entry(PlayerId(1)).or_default().kills = 2;
+        sim.step(&HashMap::new());
+        let results = sim.results.as_ref().expect("the round should be over");
+        assert_eq!(results.reason, EndReason::ScoreTarget);
+        assert_eq!(sim.round, 2);
+    }
+
+    #[test]
+    fn wall_kills_a_head_leaving_the_arena() {
+        let mut sim = running(MatchConfig::default(), 1);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(ARENA_WIDTH / 2.0 - 0.1, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            4,
+        );
+        sim.step(&HashMap::new());
+        assert_eq!(causes(&sim), vec![(PlayerId(0), None, DeathCause::Wall)]);
+    }
+
+    #[test]
+    fn wrap_keeps_the_snake_alive() {
+        let config = MatchConfig {
+            edge_mode: EdgeMode::Wrap,
+            ..Default::default()
+        };
+        let mut sim = running(config, 1);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(ARENA_WIDTH / 2.0 - 0.1, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            4,
+        );
+        sim.step(&HashMap::new());
+        assert!(sim.kills.is_empty());
+        assert!(sim.snakes[&PlayerId(0)].head().0.x < 0.0);
+    }
+
+    fn head_on(
+        rule: HeadOn,
+        length_a: usize,
+        length_b: usize,
+    ) -> Vec<(PlayerId, Option<PlayerId>, DeathCause)> {
+        let config = MatchConfig {
+            collisions: CollisionRules {
+                head_on: rule,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut sim = running(config, 2);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(-3.0, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            length_a,
+        );
+        place(
+            &mut sim,
+            1,
+            Vec2::new(3.0, 0.0),
+            Vec2::new(-CONST_SPEED, 0.0),
+            length_b,
+        );
+        sim.step(&HashMap::new());
+        causes(&sim)
+    }
+
+    #[test]
+    fn head_on_both_die() {
+        assert_eq!(
+            head_on(HeadOn::BothDie, 4, 8),
+            vec![
+                (PlayerId(0), Some(PlayerId(1)), DeathCause::HeadOn),
+                (PlayerId(1), Some(PlayerId(0)), DeathCause::HeadOn),
+            ]
+        );
+    }
+
+    #[test]
+    fn head_on_longer_wins() {
+        assert_eq!(
+            head_on(HeadOn::LongerWins, 4, 8),
+            vec![(PlayerId(0), Some(PlayerId(1)), DeathCause::HeadOn)]
+        );
+        // as long as each other: both die
+        assert_eq!(head_on(HeadOn::LongerWins, 6, 6).len(), 2);
+    }
+
+    #[test]
+    fn head_on_random_kills_exactly_one() {
+        let kills = head_on(HeadOn::Random, 4, 4);
+        assert_eq!(kills.len(), 1);
+        assert_eq!(kills[0].2, DeathCause::HeadOn);
+    }
+
+    #[test]
+    fn running_into_a_body_credits_its_owner() {
+        let mut sim = running(MatchConfig::default(), 2);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(-20.0, 0.0),
+            Vec2::new(0.0, CONST_SPEED),
+            4,
+        );
+        // a long body lying across the path of snake 0, its head far away
+        let snake = sim.snakes.get_mut(&PlayerId(1)).unwrap();
+        snake.body = (0..10)
+            .map(|i| Position(Vec2::new(30.0 - 5.0 * i as f32, 5.0)))
+            .collect();
+        snake.velocity = Velocity(Vec2::new(CONST_SPEED, 0.0));
+        sim.step(&HashMap::new());
+        assert_eq!(
+            causes(&sim),
+            vec![(PlayerId(0), Some(PlayerId(1)), DeathCause::Body)]
+        );
+    }
+
+    #[test]
+    fn eating_food_grows_the_snake_at_the_tail() {
+        let mut sim = running(MatchConfig::default(), 1);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(0.0, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            4,
+        );
+        sim.snakes.get_mut(&PlayerId(0)).unwrap().body[3] = Position(Vec2::new(-10.0, 0.0));
+        sim.spawn_food(Position(Vec2::new(1.0, 0.0)), FoodKind::Normal);
+        sim.step(&HashMap::new());
+        let snake = &sim.snakes[&PlayerId(0)];
+        assert!(sim.foods.is_empty());
+        assert_eq!(snake.body.len(), 5);
+        assert_eq!(snake.body[4], snake.body[3]);
+        assert_eq!(sim.scores[&PlayerId(0)].food_eaten, 1);
+    }
+
+    #[test]
+    fn power_ups_are_not_counted_as_food_eaten() {
+        let mut sim = running(MatchConfig::default(), 1);
+        place(
+            &mut sim,
+            0,
+            Vec2::new(0.0, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            4,
+        );
+        sim.spawn_food(Position(Vec2::new(1.0, 0.0)), FoodKind::Shrink);
+        sim.step(&HashMap::new());
+        assert!(sim.foods.is_empty());
+        assert_eq!(sim.scores[&PlayerId(0)].food_eaten, 0);
+        assert_eq!(sim.points(PlayerId(0)), 0.0);
+    }
+
+    #[test]
+    fn dead_snakes_drop_food_above_max_count() {
+        let mut config = MatchConfig::default();
+        config.food_spawn.max_count = Some(0);
+        config.food_spawn.interval_ticks = 1;
+        let mut sim = running(config, 1);
+        sim.step(&HashMap::new());
+        assert!(sim.foods.is_empty());
+        place(
+            &mut sim,
+            0,
+            Vec2::new(ARENA_WIDTH / 2.0 - 0.1, 0.0),
+            Vec2::new(CONST_SPEED, 0.0),
+            4,
+        );
+        sim.step(&HashMap::new());
+        assert_eq!(causes(&sim), vec![(PlayerId(0), None, DeathCause::Wall)]);
+        assert_eq!(sim.foods.len(), 4);
+    }
+}